@@ -0,0 +1,87 @@
+//! Measures how long the layer takes for common workloads, e.g. with `cargo bench`.
+//!
+//! os_log only exists on Apple platforms, so there's nothing to measure anywhere else.
+
+#[cfg(target_vendor = "apple")]
+fn main() {
+	use std::{
+		thread,
+		time::{Duration, Instant},
+	};
+	use tracing::{info, info_span};
+	use tracing_oslog::OsLogger;
+	use tracing_subscriber::layer::SubscriberExt;
+
+	const ROUNDS: usize = 5;
+	const SPANS: u64 = 10_000;
+	const EVENTS: u64 = 10_000;
+	const THREADS: u64 = 4;
+
+	/// Runs `f` a few times, printing how long it took per iteration in the fastest round.
+	fn bench<F: FnMut()>(name: &str, iterations: u64, mut f: F) {
+		let best = (0..ROUNDS)
+			.map(|_| {
+				let start = Instant::now();
+				f();
+				start.elapsed()
+			})
+			.min()
+			.unwrap_or(Duration::ZERO);
+		println!("{}: {:?} per iteration", name, best / iterations as u32);
+	}
+
+	let subscriber =
+		tracing_subscriber::registry().with(OsLogger::new("com.example.bench", "bench"));
+	let dispatch = tracing::Dispatch::new(subscriber);
+	tracing::dispatcher::with_default(&dispatch, || {
+		// Siblings with the same name share a cached activity name, while unique names each add
+		// one to the cache.
+		bench("10k sibling spans, same name", SPANS, || {
+			for _ in 0..SPANS {
+				let _span = info_span!("sibling");
+			}
+		});
+		bench("10k sibling spans, unique names", SPANS, || {
+			for n in 0..SPANS {
+				let _span = info_span!("sibling", n);
+			}
+		});
+		// Most events are logged outside of any span, so finding out there is none has to be
+		// cheap.
+		bench("10k events outside of any span", EVENTS, || {
+			for n in 0..EVENTS {
+				info!(n, "event");
+			}
+		});
+		bench("10k events in a span", EVENTS, || {
+			let span = info_span!("request", id = 1);
+			let _entered = span.enter();
+			for n in 0..EVENTS {
+				info!(n, "event");
+			}
+		});
+	});
+	// Every span takes the lock of the global name cache, so this shows how much it's contended.
+	bench("10k sibling spans, same name, on 4 threads", SPANS, || {
+		let threads: Vec<_> = (0..THREADS)
+			.map(|_| {
+				let dispatch = dispatch.clone();
+				thread::spawn(move || {
+					tracing::dispatcher::with_default(&dispatch, || {
+						for _ in 0..SPANS / THREADS {
+							let _span = info_span!("sibling");
+						}
+					})
+				})
+			})
+			.collect();
+		for thread in threads {
+			thread.join().expect("benchmark thread panicked");
+		}
+	});
+}
+
+#[cfg(not(target_vendor = "apple"))]
+fn main() {
+	println!("os_log is only available on Apple platforms, so there is nothing to benchmark");
+}
@@ -22,6 +22,7 @@ fn main() {
 		.allowlist_type("os_log_.*")
 		.allowlist_var("_?os_activity_.*")
 		.allowlist_var("__dso_handle")
+		.allowlist_var("_os_log_disabled")
 		.clang_args(&args)
 		.generate()
 		.expect("Unable to generate bindings");
@@ -0,0 +1,874 @@
+#![cfg_attr(not(target_vendor = "apple"), allow(dead_code))]
+
+use crate::{sink::Sink, OsLogger};
+use fnv::FnvHashMap;
+use std::{collections::BTreeMap, fmt, time::Instant};
+use tracing_core::{span::Id, Level, Metadata};
+
+/// How an event is formatted into the message that is sent to os_log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageFormat {
+	/// The span breadcrumb and the message, followed by the fields as `key=value` pairs.
+	///
+	/// This is the default.
+	KeyValue,
+	/// A JSON object with the `category`, `level`, `message` and `data` keys of a
+	/// [Sentry breadcrumb](https://develop.sentry.dev/sdk/event-payloads/breadcrumbs/), where the
+	/// category is the event's target and the data holds its fields.
+	SentryBreadcrumb,
+}
+
+/// How numeric fields are displayed.
+///
+/// The default is plain: no thousands separators and `.` as the decimal mark, the same as
+/// Rust's own formatting, no matter the locale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumericFormat {
+	/// Put between every three digits of the integer part, e.g. `Some(',')` for `1,234,567`.
+	pub thousands_separator: Option<char>,
+	/// Put between the integer and fractional parts of floats.
+	pub decimal_mark: char,
+}
+
+impl NumericFormat {
+	/// No thousands separators and `.` as the decimal mark.
+	pub const PLAIN: Self = Self {
+		thousands_separator: None,
+		decimal_mark: '.',
+	};
+
+	/// Applies this format to a number formatted by Rust.
+	pub(crate) fn apply(&self, number: &str) -> String {
+		if *self == Self::PLAIN {
+			return number.to_string();
+		}
+		let (sign, unsigned) = match number.strip_prefix('-') {
+			Some(unsigned) => ("-", unsigned),
+			None => ("", number),
+		};
+		// Anything after the digits is either the fractional part, an exponent, or `NaN`/`inf`.
+		let integer_len = unsigned
+			.find(|c: char| !c.is_ascii_digit())
+			.unwrap_or(unsigned.len());
+		let (integer, rest) = unsigned.split_at(integer_len);
+
+		let mut formatted = String::with_capacity(number.len() + integer_len / 3);
+		formatted.push_str(sign);
+		for (n, digit) in integer.chars().enumerate() {
+			if let Some(separator) = self.thousands_separator {
+				if n > 0 && (integer_len - n) % 3 == 0 {
+					formatted.push(separator);
+				}
+			}
+			formatted.push(digit);
+		}
+		match rest.strip_prefix('.') {
+			Some(fraction) => {
+				formatted.push(self.decimal_mark);
+				formatted.push_str(fraction);
+			}
+			None => formatted.push_str(rest),
+		}
+		formatted
+	}
+}
+
+impl Default for NumericFormat {
+	fn default() -> Self {
+		Self::PLAIN
+	}
+}
+
+/// What is used as the message of events that don't have a `message` field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallbackMessage {
+	/// No message, only the fields are displayed. This is the default.
+	Empty,
+	/// The event's name, which usually says where the event was logged from.
+	EventName,
+	/// The event's target.
+	Target,
+}
+
+/// What happens to `TRACE` events, since os_log has no type below debug.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceHandling {
+	/// Logged as debug, just like `DEBUG` events. This is the default.
+	AsDebug,
+	/// Not logged at all.
+	Drop,
+	/// Logged as debug, with the message prefixed by `[TRACE]` so that they can be told apart
+	/// from `DEBUG` events in Console.
+	AsDebugWithMarker,
+}
+
+/// An os_log type, which is what Console shows and filters as the level of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogType {
+	Default,
+	Info,
+	Debug,
+	Error,
+	Fault,
+}
+
+/// How [flag fields](OsLogger::flag_fields) are displayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlagStyle {
+	/// As `key=true` or `key=false`, like any other field.
+	KeyValue,
+	/// As just `key` when true, and not at all when false.
+	KeyOnlyWhenTrue,
+}
+
+/// A change in a span's lifecycle, passed to the observer set with
+/// [`OsLogger::set_span_observer`].
+#[derive(Debug, Clone)]
+pub enum SpanEvent {
+	/// A new span was created.
+	Opened { id: Id, name: &'static str },
+	/// A span was entered.
+	Entered { id: Id },
+	/// A span was exited.
+	Exited { id: Id },
+	/// A span was closed, and will not be entered again.
+	Closed { id: Id },
+}
+
+/// Where the time comes from, set with [`OsLogger::clock`].
+pub trait Clock: Send + Sync {
+	fn now(&self) -> Instant;
+}
+
+/// What a [format function](OsLogger::format_fn) builds a message from.
+pub struct FormatContext<'a> {
+	pub(crate) metadata: &'a Metadata<'a>,
+	pub(crate) spans: Vec<(&'static str, BTreeMap<String, String>)>,
+	pub(crate) fields: BTreeMap<String, String>,
+}
+
+impl<'a> FormatContext<'a> {
+	/// The metadata of the event, or of the span for [span
+	/// summaries](OsLogger::emit_span_summary_on_close).
+	pub fn metadata(&self) -> &Metadata<'a> {
+		self.metadata
+	}
+
+	/// The names and fields of the spans the event happened in, from the root down.
+	pub fn spans(&self) -> impl Iterator<Item = (&'static str, &BTreeMap<String, String>)> {
+		self.spans.iter().map(|(name, fields)| (*name, fields))
+	}
+
+	/// The fields of the event, `message` and the ones this layer adds included. String values
+	/// keep the quotes they're recorded with.
+	pub fn fields(&self) -> &BTreeMap<String, String> {
+		&self.fields
+	}
+}
+
+/// A field built by joining the values of other fields, set with [`OsLogger::composite_field`].
+#[derive(Debug)]
+pub(crate) struct CompositeField {
+	pub(crate) name: String,
+	pub(crate) sources: Vec<String>,
+	pub(crate) separator: String,
+	/// Whether every source has to be present, rather than just one.
+	pub(crate) require_all: bool,
+}
+
+type FormatFn = Box<dyn Fn(&FormatContext, &mut String) + Send + Sync>;
+
+type ActivityFilter = Box<dyn Fn(&Metadata) -> bool + Send + Sync>;
+
+pub(crate) struct Config {
+	pub(crate) subsystem: String,
+	pub(crate) category: String,
+	pub(crate) with_level: bool,
+	pub(crate) level_label: Option<fn(&Level) -> &'static str>,
+	pub(crate) with_os_log_type: bool,
+	pub(crate) field_levels: FnvHashMap<String, Level>,
+	pub(crate) dedup_consecutive: bool,
+	pub(crate) span_observer: Option<Box<dyn Fn(SpanEvent) + Send + Sync>>,
+	pub(crate) with_span_active: bool,
+	pub(crate) message_format: MessageFormat,
+	pub(crate) with_event_name: bool,
+	pub(crate) emit_span_summary_on_close: bool,
+	pub(crate) with_queue_label: bool,
+	pub(crate) owns_handle: bool,
+	pub(crate) numeric_format: NumericFormat,
+	pub(crate) fallback_message: FallbackMessage,
+	pub(crate) trace_handling: TraceHandling,
+	pub(crate) recent_logs_capacity: usize,
+	pub(crate) dedup_shared_keys: bool,
+	pub(crate) max_activity_depth: Option<usize>,
+	pub(crate) trim_trailing_newline: bool,
+	pub(crate) with_span_path_hash: bool,
+	pub(crate) with_module_path: bool,
+	pub(crate) with_callsite_count: bool,
+	pub(crate) flag_fields: FnvHashMap<String, FlagStyle>,
+	pub(crate) max_field_bytes: Option<usize>,
+	pub(crate) sinks: Vec<Box<dyn Sink>>,
+	pub(crate) with_mach_tid: bool,
+	pub(crate) clock: Option<Box<dyn Clock>>,
+	pub(crate) buffer_until_error: usize,
+	pub(crate) activity_filter: Option<ActivityFilter>,
+	pub(crate) category_fields: FnvHashMap<String, Vec<(String, String)>>,
+	pub(crate) with_level_numeric: bool,
+	pub(crate) breadcrumb_body_join: String,
+	pub(crate) with_poll_count: bool,
+	pub(crate) indent_by_depth: bool,
+	pub(crate) level_table: [LogType; 5],
+	pub(crate) with_subsystem: bool,
+	pub(crate) quote_values: bool,
+	pub(crate) with_activity_mismatch: bool,
+	pub(crate) validate_scope_balance: bool,
+	pub(crate) full_debug_level: Option<Level>,
+	pub(crate) cache_span_names: bool,
+	pub(crate) composite_fields: Vec<CompositeField>,
+	pub(crate) format_fn: Option<FormatFn>,
+}
+
+impl Config {
+	pub(crate) fn new(subsystem: &str, category: &str) -> Self {
+		Self {
+			subsystem: subsystem.to_string(),
+			category: category.to_string(),
+			with_level: false,
+			level_label: None,
+			with_os_log_type: false,
+			field_levels: FnvHashMap::default(),
+			dedup_consecutive: false,
+			span_observer: None,
+			with_span_active: false,
+			message_format: MessageFormat::KeyValue,
+			with_event_name: false,
+			emit_span_summary_on_close: false,
+			with_queue_label: false,
+			owns_handle: true,
+			numeric_format: NumericFormat::PLAIN,
+			fallback_message: FallbackMessage::Empty,
+			trace_handling: TraceHandling::AsDebug,
+			recent_logs_capacity: 0,
+			dedup_shared_keys: false,
+			max_activity_depth: None,
+			trim_trailing_newline: true,
+			with_span_path_hash: false,
+			with_module_path: false,
+			with_callsite_count: false,
+			flag_fields: FnvHashMap::default(),
+			max_field_bytes: None,
+			sinks: Vec::new(),
+			with_mach_tid: false,
+			clock: None,
+			buffer_until_error: 0,
+			activity_filter: None,
+			category_fields: FnvHashMap::default(),
+			with_level_numeric: false,
+			breadcrumb_body_join: ":".to_string(),
+			with_poll_count: false,
+			indent_by_depth: false,
+			level_table: [
+				LogType::Debug,
+				LogType::Debug,
+				LogType::Info,
+				LogType::Error,
+				LogType::Error,
+			],
+			with_subsystem: false,
+			quote_values: false,
+			with_activity_mismatch: false,
+			validate_scope_balance: false,
+			full_debug_level: None,
+			cache_span_names: true,
+			composite_fields: Vec::new(),
+			format_fn: None,
+		}
+	}
+
+	pub(crate) fn level_label(&self, level: &Level) -> &'static str {
+		match self.level_label {
+			Some(label) => label(level),
+			None => match *level {
+				Level::TRACE => "TRACE",
+				Level::DEBUG => "DEBUG",
+				Level::INFO => "INFO",
+				Level::WARN => "WARN",
+				Level::ERROR => "ERROR",
+			},
+		}
+	}
+
+	/// Passes a span lifecycle event to the span observer, if there is one.
+	///
+	/// The event is only built when it's actually needed.
+	pub(crate) fn observe<F>(&self, event: F)
+	where
+		F: FnOnce() -> SpanEvent,
+	{
+		if let Some(observer) = &self.span_observer {
+			observer(event());
+		}
+	}
+
+	/// The current time according to the configured clock.
+	pub(crate) fn now(&self) -> Instant {
+		match &self.clock {
+			Some(clock) => clock.now(),
+			None => Instant::now(),
+		}
+	}
+
+	/// Whether a field should be displayed for an event of the given level.
+	pub(crate) fn field_enabled(&self, name: &str, level: &Level) -> bool {
+		match self.field_levels.get(name) {
+			Some(max_level) => level >= max_level,
+			None => true,
+		}
+	}
+}
+
+/// Callbacks can't be printed, so this only says whether one was set.
+fn callback<T>(callback: &Option<T>) -> &'static str {
+	match callback {
+		Some(_) => "custom",
+		None => "default",
+	}
+}
+
+impl fmt::Debug for Config {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("OsLogger")
+			.field("subsystem", &self.subsystem)
+			.field("category", &self.category)
+			.field("with_level", &self.with_level)
+			.field(
+				"level_label",
+				&format_args!("{}", callback(&self.level_label)),
+			)
+			.field("with_os_log_type", &self.with_os_log_type)
+			.field("field_levels", &self.field_levels)
+			.field("dedup_consecutive", &self.dedup_consecutive)
+			.field(
+				"span_observer",
+				&format_args!("{}", callback(&self.span_observer)),
+			)
+			.field("with_span_active", &self.with_span_active)
+			.field("message_format", &self.message_format)
+			.field("with_event_name", &self.with_event_name)
+			.field(
+				"emit_span_summary_on_close",
+				&self.emit_span_summary_on_close,
+			)
+			.field("with_queue_label", &self.with_queue_label)
+			.field("owns_handle", &self.owns_handle)
+			.field("numeric_format", &self.numeric_format)
+			.field("fallback_message", &self.fallback_message)
+			.field("trace_handling", &self.trace_handling)
+			.field("recent_logs_capacity", &self.recent_logs_capacity)
+			.field("dedup_shared_keys", &self.dedup_shared_keys)
+			.field("max_activity_depth", &self.max_activity_depth)
+			.field("trim_trailing_newline", &self.trim_trailing_newline)
+			.field("with_span_path_hash", &self.with_span_path_hash)
+			.field("with_module_path", &self.with_module_path)
+			.field("with_callsite_count", &self.with_callsite_count)
+			.field("flag_fields", &self.flag_fields)
+			.field("max_field_bytes", &self.max_field_bytes)
+			.field("sinks", &self.sinks.len())
+			.field("with_mach_tid", &self.with_mach_tid)
+			.field("clock", &format_args!("{}", callback(&self.clock)))
+			.field("buffer_until_error", &self.buffer_until_error)
+			.field(
+				"activity_filter",
+				&format_args!("{}", callback(&self.activity_filter)),
+			)
+			.field("category_fields", &self.category_fields)
+			.field("with_level_numeric", &self.with_level_numeric)
+			.field("breadcrumb_body_join", &self.breadcrumb_body_join)
+			.field("with_poll_count", &self.with_poll_count)
+			.field("indent_by_depth", &self.indent_by_depth)
+			.field("level_table", &self.level_table)
+			.field("with_subsystem", &self.with_subsystem)
+			.field("quote_values", &self.quote_values)
+			.field("with_activity_mismatch", &self.with_activity_mismatch)
+			.field("validate_scope_balance", &self.validate_scope_balance)
+			.field("full_debug_level", &self.full_debug_level)
+			.field("cache_span_names", &self.cache_span_names)
+			.field("composite_fields", &self.composite_fields)
+			.field("format_fn", &format_args!("{}", callback(&self.format_fn)))
+			.finish()
+	}
+}
+
+impl OsLogger {
+	/// Sets whether or not an event's level is displayed at the start of the message.
+	pub fn with_level(mut self, display_level: bool) -> Self {
+		self.config.with_level = display_level;
+		self
+	}
+
+	/// Sets the function used to render an event's level when [`with_level`](Self::with_level) is enabled.
+	///
+	/// By default, levels are rendered as `TRACE`, `DEBUG`, `INFO`, `WARN` and `ERROR`.
+	pub fn level_label(mut self, label: fn(&Level) -> &'static str) -> Self {
+		self.config.level_label = Some(label);
+		self
+	}
+
+	/// Sets whether or not the os_log type an event was logged with is displayed, as an
+	/// `oslog_type` field.
+	///
+	/// This is mostly useful for checking which os_log type each level ends up as.
+	pub fn with_os_log_type(mut self, display_log_type: bool) -> Self {
+		self.config.with_os_log_type = display_log_type;
+		self
+	}
+
+	/// Returns a summary of the subsystem, category and every option this logger was configured
+	/// with, e.g. for attaching to a bug report.
+	///
+	/// Custom callbacks are only listed as `custom`, not printed.
+	pub fn config_summary(&self) -> String {
+		format!("{:#?}", self.config)
+	}
+
+	/// Only displays the field with the given name on events at `level` or more verbose,
+	/// e.g. only showing a `sql` field on `DEBUG` and `TRACE` events.
+	pub fn include_field_at_level<N>(mut self, name: N, level: Level) -> Self
+	where
+		N: Into<String>,
+	{
+		self.config.field_levels.insert(name.into(), level);
+		self
+	}
+
+	/// Sets whether or not a message that is identical to the last one logged from the same
+	/// callsite is suppressed.
+	///
+	/// Like syslog, a `last message repeated N times` summary is logged once any other message
+	/// is logged, after every 100 suppressed repeats, and when the logger is dropped or shut
+	/// down. The number of suppressed messages
+	/// is available from [`dropped_deduped`](Self::dropped_deduped).
+	pub fn dedup_consecutive(mut self, dedup: bool) -> Self {
+		self.config.dedup_consecutive = dedup;
+		self
+	}
+
+	/// Sets a function that is called whenever a span is opened, entered, exited or closed, e.g.
+	/// for hooking an external profiler or correlation system into the span lifecycle.
+	pub fn set_span_observer<F>(mut self, observer: F) -> Self
+	where
+		F: Fn(SpanEvent) + Send + Sync + 'static,
+	{
+		self.config.span_observer = Some(Box::new(observer));
+		self
+	}
+
+	/// Sets whether or not a `span_active` field is displayed, saying if the event's innermost
+	/// span is actually entered on the event's thread or merely its parent.
+	///
+	/// This helps when debugging async code, where an event can end up in a span that isn't
+	/// entered.
+	pub fn with_span_active(mut self, display_span_active: bool) -> Self {
+		self.config.with_span_active = display_span_active;
+		self
+	}
+
+	/// Sets how events are formatted into messages. Defaults to [`MessageFormat::KeyValue`].
+	pub fn message_format(mut self, format: MessageFormat) -> Self {
+		self.config.message_format = format;
+		self
+	}
+
+	/// Sets whether or not an event's name is displayed as an `event` field.
+	///
+	/// Event names usually identify the callsite, which tells apart events whose messages are
+	/// the same.
+	pub fn with_event_name(mut self, display_event_name: bool) -> Self {
+		self.config.with_event_name = display_event_name;
+		self
+	}
+
+	/// Sets whether or not a summary is logged whenever a span closes, with the span's name as
+	/// the message, all of its fields, and how long it was open for as an `elapsed` field.
+	///
+	/// This gives one record per span rather than per event, which is handy for analytics.
+	pub fn emit_span_summary_on_close(mut self, emit_summary: bool) -> Self {
+		self.config.emit_span_summary_on_close = emit_summary;
+		self
+	}
+
+	/// Sets whether or not the label of the dispatch queue an event was logged from is displayed
+	/// as a `queue` field, e.g. `queue=com.apple.main-thread` on the main queue.
+	///
+	/// The field is left out for queues without a label.
+	pub fn with_queue_label(mut self, display_queue_label: bool) -> Self {
+		self.config.with_queue_label = display_queue_label;
+		self
+	}
+
+	/// Sets whether or not this logger releases its os_log handle when it's dropped or
+	/// [shut down](Self::shutdown). Defaults to `true`.
+	///
+	/// Turning this off is for handles that are shared with, and released by, something else.
+	/// For a logger created with [`new`](Self::new), it leaks the handle.
+	pub fn owns_handle(mut self, owns_handle: bool) -> Self {
+		self.config.owns_handle = owns_handle;
+		self
+	}
+
+	/// Sets how numeric fields are displayed. Defaults to [`NumericFormat::PLAIN`].
+	pub fn numeric_format(mut self, format: NumericFormat) -> Self {
+		self.config.numeric_format = format;
+		self
+	}
+
+	/// Sets what is used as the message of events without a `message` field. Defaults to
+	/// [`FallbackMessage::Empty`].
+	pub fn fallback_message(mut self, fallback: FallbackMessage) -> Self {
+		self.config.fallback_message = fallback;
+		self
+	}
+
+	/// Sets what happens to `TRACE` events. Defaults to [`TraceHandling::AsDebug`].
+	pub fn trace_handling(mut self, handling: TraceHandling) -> Self {
+		self.config.trace_handling = handling;
+		self
+	}
+
+	/// Keeps the last `capacity` messages logged in memory, so that they can be read back with
+	/// [`recent_logs`](Self::recent_logs), e.g. for showing recent logs in a debug menu.
+	///
+	/// Nothing is kept by default.
+	pub fn recent_logs_capacity(mut self, capacity: usize) -> Self {
+		self.config.recent_logs_capacity = capacity;
+		self
+	}
+
+	/// Sets whether or not an event field is left out when a span in the breadcrumb already
+	/// displays the same key with the same value.
+	///
+	/// If the values differ, both are displayed, as the event's is the more specific one. This
+	/// only applies to [`MessageFormat::KeyValue`], as the other formats have no breadcrumb.
+	pub fn dedup_shared_keys(mut self, dedup: bool) -> Self {
+		self.config.dedup_shared_keys = dedup;
+		self
+	}
+
+	/// Sets how many spans deep activities are created. Spans nested deeper than that still show
+	/// up in the breadcrumb, but their events go to the activity of their closest ancestor that
+	/// has one, which bounds the cost of activities in deeply nested code.
+	///
+	/// Every span gets an activity by default.
+	pub fn max_activity_depth(mut self, depth: usize) -> Self {
+		self.config.max_activity_depth = Some(depth);
+		self
+	}
+
+	/// Sets whether or not a single newline at the end of a message is removed, so that messages
+	/// that already end with one don't show up with a blank line in Console. Defaults to `true`.
+	pub fn trim_trailing_newline(mut self, trim: bool) -> Self {
+		self.config.trim_trailing_newline = trim;
+		self
+	}
+
+	/// Sets whether or not a `path_hash` field is displayed for events in spans, holding a hash of
+	/// the names of the spans the event is in.
+	///
+	/// Events from the same path of spans share a hash, which is cheaper for log processors to
+	/// group by than the breadcrumb.
+	pub fn with_span_path_hash(mut self, display_path_hash: bool) -> Self {
+		self.config.with_span_path_hash = display_path_hash;
+		self
+	}
+
+	/// Sets whether or not the module an event was logged from is displayed as a `module` field.
+	///
+	/// This can differ from the target, which crates are free to override.
+	pub fn with_module_path(mut self, display_module_path: bool) -> Self {
+		self.config.with_module_path = display_module_path;
+		self
+	}
+
+	/// Sets whether or not a `count` field is displayed, saying how many times the event's
+	/// callsite has fired so far, including this time.
+	///
+	/// This makes runaway logging easy to spot in Console.
+	pub fn with_callsite_count(mut self, display_count: bool) -> Self {
+		self.config.with_callsite_count = display_count;
+		self
+	}
+
+	/// Sets how the boolean fields with the given names are displayed, e.g. rendering
+	/// `retrying = true` as just `retrying` with [`FlagStyle::KeyOnlyWhenTrue`].
+	///
+	/// Values other than `true` and `false` are displayed as usual.
+	pub fn flag_fields(mut self, names: &[&str], style: FlagStyle) -> Self {
+		for name in names {
+			self.config.flag_fields.insert(name.to_string(), style);
+		}
+		self
+	}
+
+	/// Caps field values at `max_bytes` bytes, cutting longer ones short with a `…`.
+	///
+	/// The cap applies to span fields too, keeping the names of their activities bounded.
+	/// Messages aren't capped.
+	pub fn max_field_bytes(mut self, max_bytes: usize) -> Self {
+		self.config.max_field_bytes = Some(max_bytes);
+		self
+	}
+
+	/// Sends every message to `sink` as well as os_log. Can be called any number of times to add
+	/// more sinks, which get messages in the order they were added.
+	pub fn with_sink<S: Sink + 'static>(mut self, sink: S) -> Self {
+		self.config.sinks.push(Box::new(sink));
+		self
+	}
+
+	/// Sets whether or not the kernel's id for the current thread is displayed as a `mach_tid`
+	/// field, matching the thread ids Instruments and `sample` show.
+	pub fn with_mach_tid(mut self, display_mach_tid: bool) -> Self {
+		self.config.with_mach_tid = display_mach_tid;
+		self
+	}
+
+	/// Sets the clock span timings are measured with, instead of [`Instant::now`], e.g. to make
+	/// them predictable in tests.
+	pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+		self.config.clock = Some(Box::new(clock));
+		self
+	}
+
+	/// Holds back events less severe than warnings, keeping the last `capacity` of them for each
+	/// thread, until the thread logs an error. The held back events are then logged ahead of the
+	/// error, giving context for it without logging everything all the time.
+	///
+	/// Disabled with a `capacity` of 0, which is the default.
+	pub fn buffer_until_error(mut self, capacity: usize) -> Self {
+		self.config.buffer_until_error = capacity;
+		self
+	}
+
+	/// Sets a function deciding which spans get an activity of their own, e.g. only the spans of
+	/// HTTP requests. The events of other spans log to the closest ancestor's activity, and the
+	/// spans are still named in breadcrumbs.
+	pub fn activity_filter<F>(mut self, filter: F) -> Self
+	where
+		F: Fn(&Metadata) -> bool + Send + Sync + 'static,
+	{
+		self.config.activity_filter = Some(Box::new(filter));
+		self
+	}
+
+	/// Adds fields to every event logged to `category`, e.g. `component=http` to the events of the
+	/// `http` category. Fields the event sets itself take precedence.
+	///
+	/// Calling this again for the same category replaces its fields.
+	pub fn category_fields(mut self, category: &str, fields: &[(&str, &str)]) -> Self {
+		let fields = fields
+			.iter()
+			.map(|(k, v)| (k.to_string(), v.to_string()))
+			.collect();
+		self.config
+			.category_fields
+			.insert(category.to_string(), fields);
+		self
+	}
+
+	/// Sets whether or not the event's level is displayed as a number in a `level_num` field, for
+	/// log processors that sort or filter by severity. The scale goes from 0 for `TRACE` up to 4
+	/// for `ERROR`.
+	pub fn with_level_numeric(mut self, display_level_num: bool) -> Self {
+		self.config.with_level_numeric = display_level_num;
+		self
+	}
+
+	/// Sets what goes between the span breadcrumb and the rest of the message, `":"` by default.
+	///
+	/// Events outside of any span have no breadcrumb, and so no separator either.
+	pub fn breadcrumb_body_join(mut self, join: &str) -> Self {
+		self.config.breadcrumb_body_join = join.to_string();
+		self
+	}
+
+	/// Sets whether or not the [span summary](Self::emit_span_summary_on_close) includes how many
+	/// times the span was entered as a `polls` field.
+	///
+	/// For the span of an instrumented future, that's how many times the future was polled, which
+	/// makes futures that are polled excessively easy to spot.
+	pub fn with_poll_count(mut self, display_polls: bool) -> Self {
+		self.config.with_poll_count = display_polls;
+		self
+	}
+
+	/// Sets whether or not messages are indented by two spaces for each span the event is in,
+	/// making nesting visible when following the messages of a [sink](Self::with_sink) in a
+	/// terminal.
+	pub fn indent_by_depth(mut self, indent: bool) -> Self {
+		self.config.indent_by_depth = indent;
+		self
+	}
+
+	/// Sets the os_log types that events of each level are logged with, from `TRACE` up to
+	/// `ERROR`.
+	///
+	/// By default `TRACE` and `DEBUG` are logged as [`LogType::Debug`], `INFO` as
+	/// [`LogType::Info`], and `WARN` and `ERROR` as [`LogType::Error`].
+	pub fn with_level_table(mut self, table: [LogType; 5]) -> Self {
+		self.config.level_table = table;
+		self
+	}
+
+	/// Sets whether or not the logger's subsystem is displayed as a `subsystem` field, to tell
+	/// apart the events of several loggers once they're collected together.
+	pub fn with_subsystem(mut self, display_subsystem: bool) -> Self {
+		self.config.with_subsystem = display_subsystem;
+		self
+	}
+
+	/// Sets whether or not field values are quoted logfmt-style in the key-value format: values
+	/// with whitespace, `=`s, quotes or escapes are wrapped in quotes, escaped like Rust strings,
+	/// and all other values are left bare, strings included.
+	///
+	/// This makes the fields reliably parseable.
+	pub fn quote_values(mut self, quote: bool) -> Self {
+		self.config.quote_values = quote;
+		self
+	}
+
+	/// Sets whether or not an `activity_mismatch=true` field is displayed on events that os_log
+	/// won't file under their span's activity.
+	///
+	/// os_log files events under the activity this thread entered last, so this happens when the
+	/// event's span isn't entered on the thread, e.g. for an event with an explicit `parent`.
+	pub fn with_activity_mismatch(mut self, display_mismatch: bool) -> Self {
+		self.config.with_activity_mismatch = display_mismatch;
+		self
+	}
+
+	/// Sets whether or not spans are checked to be exited on the thread that entered them, in the
+	/// reverse order they were entered, panicking if not. Unbalanced scopes leave os_log filing
+	/// events under the wrong activities.
+	///
+	/// The check is only made in debug builds. Spans entered before the logger was set up fail it
+	/// too.
+	pub fn validate_scope_balance(mut self, validate: bool) -> Self {
+		self.config.validate_scope_balance = validate;
+		self
+	}
+
+	/// Exempts the `Debug` values of events at `level` or more severe from the
+	/// [field length cap](Self::max_field_bytes), so that e.g. errors carry full structures.
+	pub fn full_debug_at_level(mut self, level: Level) -> Self {
+		self.config.full_debug_level = Some(level);
+		self
+	}
+
+	/// Sets whether or not the names of span activities are cached, which is the default.
+	///
+	/// With caching off, each activity gets a fresh copy of its name that lives as long as the
+	/// span, so that spans with unique names, e.g. from high-cardinality fields, don't grow the
+	/// cache without ever hitting it.
+	pub fn cache_span_names(mut self, cache: bool) -> Self {
+		self.config.cache_span_names = cache;
+		self
+	}
+
+	/// Adds a field called `name` to events, joining the values of the `sources` fields with
+	/// `separator`, e.g. `corr=42:7f3a` from `user_id` and `request_id`. Sources are looked up on
+	/// the event first, then on its spans from the innermost outwards.
+	///
+	/// The field is only added when all the sources are present.
+	pub fn composite_field(self, name: &str, sources: &[&str], separator: &str) -> Self {
+		self.add_composite_field(name, sources, separator, true)
+	}
+
+	/// Like [`composite_field`](Self::composite_field), but adds the field when any of the sources
+	/// are present, joining just those.
+	pub fn partial_composite_field(self, name: &str, sources: &[&str], separator: &str) -> Self {
+		self.add_composite_field(name, sources, separator, false)
+	}
+
+	fn add_composite_field(
+		mut self,
+		name: &str,
+		sources: &[&str],
+		separator: &str,
+		require_all: bool,
+	) -> Self {
+		self.config.composite_fields.push(CompositeField {
+			name: name.to_string(),
+			sources: sources.iter().map(|source| source.to_string()).collect(),
+			separator: separator.to_string(),
+			require_all,
+		});
+		self
+	}
+
+	/// Sets a function that writes each message itself, in place of the configured
+	/// [`MessageFormat`] and every other formatting option, including
+	/// [`dedup_shared_keys`](Self::dedup_shared_keys) and [`indent_by_depth`](Self::indent_by_depth).
+	/// Messages are still logged with the usual os_log types, categories and activities.
+	pub fn format_fn<F>(mut self, format: F) -> Self
+	where
+		F: Fn(&FormatContext, &mut String) + Send + Sync + 'static,
+	{
+		self.config.format_fn = Some(Box::new(format));
+		self
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const GERMAN: NumericFormat = NumericFormat {
+		thousands_separator: Some('.'),
+		decimal_mark: ',',
+	};
+
+	#[test]
+	fn numeric_format_separates_thousands() {
+		let format = NumericFormat {
+			thousands_separator: Some(','),
+			..NumericFormat::PLAIN
+		};
+		assert_eq!(format.apply("1234567"), "1,234,567");
+		assert_eq!(format.apply("-1234567"), "-1,234,567");
+		assert_eq!(format.apply("-123"), "-123");
+	}
+
+	#[test]
+	fn numeric_format_keeps_exponents_and_non_numbers() {
+		assert_eq!(GERMAN.apply(&format!("{:?}", 1e21)), "1e21");
+		assert_eq!(GERMAN.apply(&format!("{:?}", f64::NAN)), "NaN");
+		assert_eq!(GERMAN.apply(&format!("{:?}", f64::NEG_INFINITY)), "-inf");
+	}
+
+	#[test]
+	fn numeric_format_uses_the_decimal_mark() {
+		assert_eq!(GERMAN.apply("-1234.5"), "-1.234,5");
+		assert_eq!(GERMAN.apply("0.25"), "0,25");
+		assert_eq!(NumericFormat::PLAIN.apply("1234.5"), "1234.5");
+	}
+
+	#[test]
+	fn config_summary_lists_with_span_active() {
+		let summary = OsLogger::new("com.example.test", "test")
+			.with_span_active(true)
+			.config_summary();
+		assert!(summary.contains("with_span_active: true"), "{}", summary);
+	}
+
+	#[test]
+	fn config_summary_lists_message_format() {
+		let summary = OsLogger::new("com.example.test", "test")
+			.message_format(MessageFormat::SentryBreadcrumb)
+			.config_summary();
+		assert!(
+			summary.contains("message_format: SentryBreadcrumb"),
+			"{}",
+			summary
+		);
+	}
+}
@@ -0,0 +1,65 @@
+use std::fmt::Write;
+
+/// Appends `value` to `out` as a JSON string, quotes included.
+pub fn push_str(out: &mut String, value: &str) {
+	out.push('"');
+	for c in value.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if c.is_control() => {
+				let _ = write!(out, "\\u{:04x}", c as u32);
+			}
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+}
+
+/// Returns whether `value` is a number as JSON writes them, e.g. `-1.5e3`, but not `1.` or `NaN`.
+pub fn is_number(value: &str) -> bool {
+	let digits =
+		|value: &str| value.len() - value.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+	let value = value.strip_prefix('-').unwrap_or(value);
+	let integer = digits(value);
+	if integer == 0 || (integer > 1 && value.starts_with('0')) {
+		return false;
+	}
+	let mut rest = &value[integer..];
+	if let Some(fraction) = rest.strip_prefix('.') {
+		let len = digits(fraction);
+		if len == 0 {
+			return false;
+		}
+		rest = &fraction[len..];
+	}
+	if let Some(exponent) = rest.strip_prefix(['e', 'E']) {
+		let exponent = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+		let len = digits(exponent);
+		if len == 0 {
+			return false;
+		}
+		rest = &exponent[len..];
+	}
+	rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn numbers_are_recognized() {
+		for number in ["0", "5", "-12", "1.5", "1e21", "-2.5E-3"] {
+			assert!(is_number(number), "{}", number);
+		}
+		for other in [
+			"", "-", "01", "1.", ".5", "1e", "NaN", "inf", "1,234", "0x10", "5 ",
+		] {
+			assert!(!is_number(other), "{}", other);
+		}
+	}
+}
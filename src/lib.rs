@@ -1,7 +1,17 @@
 #![deny(clippy::complexity, clippy::correctness, clippy::perf, clippy::style)]
+mod config;
+mod sink;
+
+pub use config::{
+	Clock, FallbackMessage, FlagStyle, FormatContext, LogType, MessageFormat, NumericFormat,
+	SpanEvent, TraceHandling,
+};
+pub use sink::{LogRecord, Sink};
+
 cfg_if::cfg_if! {
 	if #[cfg(target_vendor = "apple")] {
 		mod ffi;
+		mod json;
 		mod logger;
 		mod visitor;
 		pub use logger::*;
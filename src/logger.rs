@@ -1,33 +1,323 @@
 use crate::{
+	config::{
+		Config, FallbackMessage, FlagStyle, FormatContext, LogType, MessageFormat, SpanEvent,
+		TraceHandling,
+	},
 	ffi::{
-		__dso_handle, _os_activity_create, _os_activity_current, mach_header,
-		os_activity_flag_t_OS_ACTIVITY_FLAG_DEFAULT, os_activity_scope_enter,
-		os_activity_scope_leave, os_activity_scope_state_s, os_activity_t, os_log_create, os_log_t,
-		os_log_type_t_OS_LOG_TYPE_DEBUG, os_log_type_t_OS_LOG_TYPE_ERROR,
-		os_log_type_t_OS_LOG_TYPE_INFO, os_release, wrapped_os_log_with_type,
+		__dso_handle, _os_activity_create, _os_activity_current, _os_activity_label_useraction,
+		_os_log_disabled, mach_header, os_activity_flag_t_OS_ACTIVITY_FLAG_DEFAULT,
+		os_activity_get_identifier, os_activity_scope_enter, os_activity_scope_leave,
+		os_activity_scope_state_s, os_activity_t, os_log_create, os_log_t, os_log_type_t,
+		os_log_type_t_OS_LOG_TYPE_DEBUG, os_log_type_t_OS_LOG_TYPE_DEFAULT,
+		os_log_type_t_OS_LOG_TYPE_ERROR, os_log_type_t_OS_LOG_TYPE_FAULT,
+		os_log_type_t_OS_LOG_TYPE_INFO, os_release, wrapped_current_queue_label, wrapped_mach_tid,
+		wrapped_os_log_with_type,
 	},
-	visitor::{AttributeMap, FieldVisitor},
+	json,
+	sink::LogRecord,
+	visitor::{truncated, unquoted, AttributeMap, FieldVisitor},
 };
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHasher};
 use once_cell::sync::Lazy;
-use parking_lot::Mutex;
-use std::{ffi::CString, ops::Deref};
+use parking_lot::{Mutex, RwLock};
+use std::{
+	borrow::Cow,
+	cell::RefCell,
+	collections::VecDeque,
+	ffi::{CStr, CString},
+	hash::{Hash, Hasher},
+	ops::Deref,
+	os::raw::{c_char, c_void},
+	sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+	time::Instant,
+};
 use string_builder::Builder as StringBuilder;
 use tracing_core::{
+	callsite::Identifier,
 	span::{Attributes, Id},
-	Event, Level, Subscriber,
+	Event, Level, Metadata, Subscriber,
 };
 use tracing_subscriber::{
 	layer::{Context, Layer},
-	registry::LookupSpan,
+	registry::{LookupSpan, Scope},
 };
 
+/// Activity names and labels, which os_activity keeps pointers to for as long as the activity
+/// exists. Entries are never removed, as there's no telling when os_activity is done with them.
 static NAMES: Lazy<Mutex<FnvHashMap<String, CString>>> =
 	Lazy::new(|| Mutex::new(FnvHashMap::default()));
 
+/// The category used when os_log rejects the one an `OsLogger` was created with.
+const FALLBACK_CATEGORY: &str = "default";
+
+/// Gives every `OsLogger` its own key into [`BUFFERED`].
+static NEXT_LOGGER_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+	/// The events each logger held back on this thread for [`OsLogger::buffer_until_error`],
+	/// keyed by the logger's id.
+	///
+	/// Keeping them per thread means they go away with the thread, rather than piling up for
+	/// every thread that ever logged.
+	static BUFFERED: RefCell<FnvHashMap<usize, VecDeque<BufferedMessage>>> =
+		RefCell::new(FnvHashMap::default());
+}
+
+thread_local! {
+	/// The activity scopes entered on this thread, innermost last.
+	///
+	/// os_activity scopes belong to the thread that entered them, so this has to be per thread
+	/// rather than per logger. Instrumented futures enter and exit their span around every poll,
+	/// so a scope never has to move between threads even when the task does.
+	static SCOPES: RefCell<Vec<EnteredScope>> = const { RefCell::new(Vec::new()) };
+}
+
+struct EnteredScope {
+	id: Id,
+	name: &'static str,
+	activity: os_activity_t,
+	/// The state of the activity scope, or `None` for spans without an activity of their own,
+	/// which are only here to be listed by [`OsLogger::dump_active_spans`].
+	state: Option<Box<os_activity_scope_state_s>>,
+}
+
+/// Returns a pointer to a C string with the given name that lives for the rest of the process.
+fn intern_name(name: String) -> *const c_char {
+	// Moving a `CString` around inside the map doesn't move its heap buffer, so the pointer stays
+	// valid.
+	NAMES
+		.lock()
+		.entry(name.clone())
+		.or_insert_with(|| c_string(name))
+		.as_ptr()
+}
+
+/// Converts `value` to a C string, dropping any NUL bytes rather than failing on them.
+fn c_string(value: String) -> CString {
+	CString::new(value).unwrap_or_else(|err| {
+		let mut bytes = err.into_vec();
+		bytes.retain(|&byte| byte != 0);
+		CString::new(bytes).expect("all NUL bytes were removed")
+	})
+}
+
+/// Releases an os_log handle or activity.
+fn release(object: *mut c_void) {
+	#[cfg(test)]
+	tests::RELEASED.with(|released| released.borrow_mut().push(object as usize));
+	unsafe { os_release(object) };
+}
+
+/// Creates an os_log handle for a category, or returns `None` if os_log rejects the category.
+#[allow(static_mut_refs)]
+fn create_logger(subsystem: &CStr, category: &str) -> Option<os_log_t> {
+	let category = CString::new(category).ok()?;
+	let logger = unsafe { os_log_create(subsystem.as_ptr(), category.as_ptr()) };
+	let disabled = unsafe { &mut _os_log_disabled as os_log_t };
+	// The disabled handle is a static sentinel, so it must never be released either.
+	if logger == disabled {
+		None
+	} else {
+		Some(logger)
+	}
+}
+
+/// The warning logged when os_log rejects `category`, saying that `fallback` is used instead.
+fn rejected_category_warning(category: &str, fallback: &str) -> String {
+	format!(
+		"os_log rejected the category {:?}, logging to {:?} instead",
+		category, fallback
+	)
+}
+
+/// The diagnostic logged when `hook` is called for a span the registry no longer has.
+fn missing_span_message(hook: &str, id: &Id) -> String {
+	format!(
+		"{} skipped span {:?}, which the registry no longer has",
+		hook, id
+	)
+}
+
+/// Returns the label of the dispatch queue the current thread is running, e.g.
+/// `com.apple.main-thread`, or `None` if the queue doesn't have one.
+fn current_queue_label() -> Option<String> {
+	let label = unsafe { wrapped_current_queue_label() };
+	if label.is_null() {
+		return None;
+	}
+	let label = unsafe { CStr::from_ptr(label) }.to_string_lossy();
+	if label.is_empty() {
+		None
+	} else {
+		Some(label.into_owned())
+	}
+}
+
+/// The field a span can set to a comma separated list of its field names, to display those fields
+/// first and in that order in breadcrumbs.
+const FIELD_ORDER_FIELD: &str = "os_log.field_order";
+
+/// Returns the fields of a span to display in breadcrumbs, in the order the span asked for with
+/// [`FIELD_ORDER_FIELD`], followed by any it didn't mention.
+fn breadcrumb_fields(attributes: &AttributeMap) -> Vec<(&String, &String)> {
+	let mut fields: Vec<_> = attributes
+		.iter()
+		.filter(|(k, _)| !is_hidden_field(k))
+		.collect();
+	if let Some(order) = attributes.get(FIELD_ORDER_FIELD) {
+		let order: Vec<_> = unquoted(order).split(',').map(str::trim).collect();
+		// Stable, so unlisted fields keep their usual order after the listed ones.
+		fields.sort_by_key(|(k, _)| {
+			order
+				.iter()
+				.position(|name| name == k)
+				.unwrap_or(order.len())
+		});
+	}
+	fields
+}
+
+/// Returns the kernel's id for the current thread, or `None` if it can't be looked up.
+fn mach_tid() -> Option<u64> {
+	let mut tid = 0;
+	if unsafe { wrapped_mach_tid(&mut tid) } == 0 {
+		Some(tid)
+	} else {
+		None
+	}
+}
+
+/// The field a span or event can set to log to a different category of the logger's subsystem.
+///
+/// Spans pass their category on to their children.
+const CATEGORY_FIELD: &str = "os_log.category";
+
+/// Whether a field is only there for `log` compatibility or to configure this layer, rather than
+/// to be displayed.
+fn is_hidden_field(name: &str) -> bool {
+	name.starts_with("log.") || name.starts_with("os_log.")
+}
+
+/// Returns the placeholder os_activity uses for whichever activity is current, which spans without
+/// an ancestor activity inherit.
+#[allow(static_mut_refs)]
+fn current_activity() -> os_activity_t {
+	unsafe { &mut _os_activity_current as *mut _ }
+}
+
+/// Returns the name of a span's activity, made of its target, name and fields.
+fn activity_name(metadata: &Metadata, attributes: &AttributeMap) -> String {
+	let function_name = [metadata.target(), metadata.name()].join("::");
+	format!(
+		"{}({})",
+		function_name,
+		attributes
+			.iter()
+			.map(|(k, v)| format!("{}: {}", k, v))
+			.collect::<Vec<_>>()
+			.join(", ")
+	)
+}
+
+/// Creates an activity for a span. `name` has to outlive the activity.
+#[allow(static_mut_refs)]
+fn create_activity(name: *const c_char, parent: os_activity_t) -> os_activity_t {
+	unsafe {
+		_os_activity_create(
+			&mut __dso_handle as *mut mach_header as *mut _,
+			name,
+			parent,
+			os_activity_flag_t_OS_ACTIVITY_FLAG_DEFAULT,
+		)
+	}
+}
+
+/// Returns the value os_log knows a type by.
+fn os_log_type(log_type: LogType) -> os_log_type_t {
+	match log_type {
+		LogType::Default => os_log_type_t_OS_LOG_TYPE_DEFAULT,
+		LogType::Info => os_log_type_t_OS_LOG_TYPE_INFO,
+		LogType::Debug => os_log_type_t_OS_LOG_TYPE_DEBUG,
+		LogType::Error => os_log_type_t_OS_LOG_TYPE_ERROR,
+		LogType::Fault => os_log_type_t_OS_LOG_TYPE_FAULT,
+	}
+}
+
+/// Returns where a level falls on the severity scale of the `level_num` field, from 0 for `TRACE`
+/// up to 4 for `ERROR`.
+fn level_number(level: &Level) -> i32 {
+	match *level {
+		Level::TRACE => 0,
+		Level::DEBUG => 1,
+		Level::INFO => 2,
+		Level::WARN => 3,
+		Level::ERROR => 4,
+	}
+}
+
+/// Returns the name Console uses for an os_log type.
+#[allow(non_upper_case_globals)]
+fn log_type_name(log_type: os_log_type_t) -> &'static str {
+	match log_type {
+		os_log_type_t_OS_LOG_TYPE_DEBUG => "debug",
+		os_log_type_t_OS_LOG_TYPE_INFO => "info",
+		os_log_type_t_OS_LOG_TYPE_ERROR => "error",
+		os_log_type_t_OS_LOG_TYPE_FAULT => "fault",
+		os_log_type_t_OS_LOG_TYPE_DEFAULT => "default",
+		_ => "unknown",
+	}
+}
+
+/// How many repeats [`OsLogger::dedup_consecutive`] suppresses before logging a summary anyway.
+const DEDUP_SUMMARY_INTERVAL: u64 = 100;
+
+/// An event held back by [`OsLogger::buffer_until_error`].
+struct BufferedMessage {
+	logger: os_log_t,
+	category: String,
+	level: os_log_type_t,
+	message: String,
+}
+
+struct LastMessage {
+	hash: u64,
+	repeats: u64,
+	/// Where the summary of the repeats is logged to, which is where the message went.
+	logger: os_log_t,
+	category: String,
+	level: os_log_type_t,
+}
+
+impl LastMessage {
+	/// Returns the summary of the repeats suppressed since the last one, if there were any.
+	fn take_summary(&mut self) -> Option<(os_log_t, String, os_log_type_t, String)> {
+		match std::mem::take(&mut self.repeats) {
+			0 => None,
+			repeats => Some((
+				self.logger,
+				self.category.clone(),
+				self.level,
+				format!("last message repeated {} times", repeats),
+			)),
+		}
+	}
+}
+
 struct Activity {
+	/// The span's own activity, or the closest ancestor's if it doesn't have one.
 	activity: os_activity_t,
+	/// The name of `activity` if it isn't cached, which is dropped after the activity is released.
+	_name: Option<CString>,
+	/// Whether `activity` was created for this span, rather than borrowed from an ancestor.
+	owns_activity: bool,
+	/// How many spans deep this span is, starting at 1 for spans without a parent.
+	depth: usize,
 	attributes: AttributeMap,
+	/// How many times the span has been entered in total.
+	polls: u64,
+	created: Instant,
+	/// The category set with [`CATEGORY_FIELD`] by this span or the closest ancestor that set one.
+	category: Option<String>,
 }
 
 // lol
@@ -41,15 +331,23 @@ impl Deref for Activity {
 }
 impl Drop for Activity {
 	fn drop(&mut self) {
-		unsafe {
-			os_release(self.activity as *mut _);
+		if self.owns_activity {
+			release(self.activity as *mut _);
 		}
 	}
 }
 
 pub struct OsLogger {
 	logger: os_log_t,
-	state: os_activity_scope_state_s,
+	shut_down: AtomicBool,
+	category_loggers: Mutex<FnvHashMap<String, os_log_t>>,
+	last_messages: Mutex<FnvHashMap<Identifier, LastMessage>>,
+	callsite_counts: RwLock<FnvHashMap<Identifier, AtomicU64>>,
+	recent_logs: Mutex<VecDeque<String>>,
+	dropped_deduped: AtomicU64,
+	/// This logger's key into [`BUFFERED`].
+	id: usize,
+	pub(crate) config: Config,
 }
 
 impl OsLogger {
@@ -59,182 +357,2054 @@ impl OsLogger {
 	///
 	/// * `subsystem` - An identifier string, in reverse DNS notation, that represents the subsystem that’s performing logging, for example, `com.your_company.your_subsystem_name`. The subsystem is used for categorization and filtering of related log messages, as well as for grouping related logging settings.
 	/// * `category` - A category within the specified subsystem. The system uses the category to categorize and filter related log messages, as well as to group related logging settings within the subsystem’s settings. A category’s logging settings override those of the parent subsystem.
+	///
+	/// If os_log rejects the category, e.g. because it contains a NUL byte, the `default` category is used instead and a warning saying so is logged to it.
 	pub fn new<S, C>(subsystem: S, category: C) -> Self
 	where
 		S: AsRef<str>,
 		C: AsRef<str>,
 	{
-		let subsystem = CString::new(subsystem.as_ref())
+		let mut config = Config::new(subsystem.as_ref(), category.as_ref());
+		let subsystem = CString::new(config.subsystem.as_str())
 			.expect("failed to construct C string from subsystem name");
-		let category = CString::new(category.as_ref())
-			.expect("failed to construct C string from category name");
-		let logger = unsafe { os_log_create(subsystem.as_ptr(), category.as_ptr()) };
-		let state = unsafe { std::mem::zeroed() };
-		Self { logger, state }
+		let logger = match create_logger(&subsystem, &config.category) {
+			Some(logger) => logger,
+			None => {
+				let logger = create_logger(&subsystem, FALLBACK_CATEGORY)
+					.expect("os_log rejected the fallback category");
+				let warning = c_string(rejected_category_warning(
+					&config.category,
+					FALLBACK_CATEGORY,
+				));
+				unsafe {
+					wrapped_os_log_with_type(
+						logger,
+						os_log_type_t_OS_LOG_TYPE_ERROR,
+						warning.as_ptr(),
+					)
+				};
+				config.category = FALLBACK_CATEGORY.to_string();
+				logger
+			}
+		};
+		Self {
+			logger,
+			shut_down: AtomicBool::new(false),
+			category_loggers: Mutex::new(FnvHashMap::default()),
+			last_messages: Mutex::new(FnvHashMap::default()),
+			callsite_counts: RwLock::new(FnvHashMap::default()),
+			recent_logs: Mutex::new(VecDeque::new()),
+			dropped_deduped: AtomicU64::new(0),
+			id: NEXT_LOGGER_ID.fetch_add(1, Ordering::Relaxed),
+			config,
+		}
+	}
+
+	/// Labels the activity that is currently active on this thread as a user action, which
+	/// Console shows in its activity view.
+	///
+	/// Does nothing if there is no active activity, e.g. when called outside of any span. Apple
+	/// only documents this for activities that AppKit or UIKit created, e.g. while handling a
+	/// user event, so Console may not show labels on the activities of spans.
+	#[allow(static_mut_refs)]
+	pub fn label_current_activity<L>(label: L)
+	where
+		L: AsRef<str>,
+	{
+		let current = unsafe { &mut _os_activity_current as *mut _ };
+		if unsafe { os_activity_get_identifier(current, std::ptr::null_mut()) } == 0 {
+			return;
+		}
+		// The label has to outlive the activity, so it goes through the same cache as span names.
+		let label = intern_name(label.as_ref().to_string());
+		// `os_activity_label_useraction` is a macro that only takes string literals, so this calls
+		// the function behind it directly.
+		unsafe {
+			_os_activity_label_useraction(&mut __dso_handle as *mut mach_header as *mut _, label)
+		};
+	}
+
+	/// Logs a message with the given fields straight to os_log, e.g. for diagnostics the user
+	/// explicitly asked for.
+	///
+	/// Unlike events, this is never suppressed by
+	/// [`dedup_consecutive`](Self::dedup_consecutive) and every field is displayed, regardless of
+	/// [`include_field_at_level`](Self::include_field_at_level). The message is always formatted
+	/// as [`MessageFormat::KeyValue`], without a span breadcrumb, and its fields are displayed
+	/// in the given order.
+	pub fn force_log(&self, level: Level, message: &str, fields: &[(&str, &str)]) {
+		if self.shut_down.load(Ordering::Acquire) {
+			return;
+		}
+		let mut formatted = StringBuilder::default();
+		if self.config.with_level {
+			formatted.append(self.config.level_label(&level));
+			formatted.append(" ");
+		}
+		formatted.append(message);
+		let has_message = !message.is_empty();
+		let mut n = 0;
+		for (k, v) in fields {
+			let v = truncated(v.to_string(), self.config.max_field_bytes);
+			self.append_field(&mut formatted, &mut n, has_message, k, &v);
+		}
+		self.log(
+			self.logger,
+			&self.config.category,
+			self.log_type(&level),
+			formatted.string().expect("build string error"),
+		);
+	}
+
+	/// Logs the spans that are currently entered on this thread, outermost first, with their
+	/// names and ids, e.g. `active spans: request(1) > query(4)`.
+	///
+	/// This helps with figuring out which activity an event ends up in. Only spans that are
+	/// actually entered are known, not ones an event could merely be given as a parent.
+	pub fn dump_active_spans(&self) {
+		if self.shut_down.load(Ordering::Acquire) {
+			return;
+		}
+		let spans = SCOPES.with(|scopes| {
+			scopes
+				.borrow()
+				.iter()
+				.map(|scope| format!("{}({})", scope.name, scope.id.into_u64()))
+				.collect::<Vec<_>>()
+		});
+		let message = if spans.is_empty() {
+			"active spans: none".to_string()
+		} else {
+			format!("active spans: {}", spans.join(" > "))
+		};
+		self.log(
+			self.logger,
+			&self.config.category,
+			os_log_type_t_OS_LOG_TYPE_DEFAULT,
+			message,
+		);
+	}
+
+	/// Returns the messages kept by [`recent_logs_capacity`](Self::recent_logs_capacity), oldest
+	/// first.
+	pub fn recent_logs(&self) -> Vec<String> {
+		self.recent_logs.lock().iter().cloned().collect()
+	}
+
+	/// Returns how many messages have been suppressed by
+	/// [`dedup_consecutive`](Self::dedup_consecutive) so far.
+	pub fn dropped_deduped(&self) -> u64 {
+		self.dropped_deduped.load(Ordering::Relaxed)
+	}
+
+	/// Releases this logger's os_log handles and clears its caches, for apps that want to clean
+	/// up deterministically before exiting.
+	///
+	/// Afterwards, this logger ignores every new span and event. Calling it more than once does
+	/// nothing. Activity names are shared by all loggers and still used by open activities, so
+	/// they're kept.
+	pub fn shutdown(&self) {
+		if self.shut_down.swap(true, Ordering::AcqRel) {
+			return;
+		}
+		self.flush_repeats();
+		self.release_handles();
+		self.discard_buffered();
+		self.last_messages.lock().clear();
+		self.callsite_counts.write().clear();
 	}
 }
 
 unsafe impl Sync for OsLogger {}
 unsafe impl Send for OsLogger {}
 
+impl OsLogger {
+	/// Logs at debug level that `hook` skipped the span `id`, unless the logger is shut down and
+	/// has nothing to log to.
+	fn span_not_found(&self, hook: &str, id: &Id) {
+		if !self.shut_down.load(Ordering::Acquire) {
+			self.log(
+				self.logger,
+				&self.config.category,
+				os_log_type_t_OS_LOG_TYPE_DEBUG,
+				missing_span_message(hook, id),
+			);
+		}
+	}
+
+	fn log(&self, logger: os_log_t, category: &str, level: os_log_type_t, mut message: String) {
+		if self.config.trim_trailing_newline && message.ends_with('\n') {
+			message.pop();
+			if message.ends_with('\r') {
+				message.pop();
+			}
+		}
+		let capacity = self.config.recent_logs_capacity;
+		if capacity > 0 {
+			let mut recent_logs = self.recent_logs.lock();
+			if recent_logs.len() == capacity {
+				recent_logs.pop_front();
+			}
+			recent_logs.push_back(message.clone());
+		}
+		if !self.config.sinks.is_empty() {
+			let record = LogRecord {
+				subsystem: &self.config.subsystem,
+				category,
+				log_type: log_type_name(level),
+				message: &message,
+			};
+			for sink in &self.config.sinks {
+				sink.emit(&record);
+			}
+		}
+		let message = c_string(message);
+		unsafe { wrapped_os_log_with_type(logger, level, message.as_ptr()) };
+	}
+
+	/// Holds back a message until the current thread logs an error, forgetting the oldest one held
+	/// back if there are too many.
+	fn buffer(&self, logger: os_log_t, category: &str, level: os_log_type_t, message: String) {
+		BUFFERED.with(|buffered| {
+			let mut buffered = buffered.borrow_mut();
+			let messages = buffered.entry(self.id).or_default();
+			if messages.len() == self.config.buffer_until_error {
+				messages.pop_front();
+			}
+			messages.push_back(BufferedMessage {
+				logger,
+				category: category.to_string(),
+				level,
+				message,
+			});
+		});
+	}
+
+	/// Logs the messages the current thread held back, oldest first.
+	fn flush_buffered(&self) {
+		let messages = BUFFERED.with(|buffered| buffered.borrow_mut().remove(&self.id));
+		for buffered in messages.into_iter().flatten() {
+			self.log(
+				buffered.logger,
+				&buffered.category,
+				buffered.level,
+				buffered.message,
+			);
+		}
+	}
+
+	/// Forgets the messages the current thread held back. Other threads' are forgotten when
+	/// those threads exit.
+	fn discard_buffered(&self) {
+		// The thread local may already be gone if this runs while the thread exits.
+		let _ = BUFFERED.try_with(|buffered| buffered.borrow_mut().remove(&self.id));
+	}
+
+	/// Returns the os_log type events of the given level are logged with.
+	fn log_type(&self, level: &Level) -> os_log_type_t {
+		os_log_type(self.config.level_table[level_number(level) as usize])
+	}
+
+	/// Returns the os_log handle for a category of this logger's subsystem, creating it the first
+	/// time the category is used, along with the category it actually logs to.
+	///
+	/// Categories os_log rejects end up in the logger's own category, with a warning logged the
+	/// first time. Every category is kept until the logger is dropped, so categories shouldn't be
+	/// built from unbounded data like ids.
+	fn category_logger<'c>(&'c self, category: Option<&'c str>) -> (os_log_t, &'c str) {
+		let own = (self.logger, self.config.category.as_str());
+		let category = match category {
+			Some(category) if category != self.config.category => category,
+			_ => return own,
+		};
+		let mut loggers = self.category_loggers.lock();
+		let logger = match loggers.get(category) {
+			Some(logger) => *logger,
+			None => {
+				let subsystem = CString::new(self.config.subsystem.as_str())
+					.expect("failed to construct C string from subsystem name");
+				let created = create_logger(&subsystem, category);
+				loggers.insert(category.to_string(), created.unwrap_or(self.logger));
+				// Unlocked before warning, as a sink could log through tracing again.
+				drop(loggers);
+				match created {
+					Some(logger) => logger,
+					None => {
+						self.log(
+							self.logger,
+							&self.config.category,
+							os_log_type_t_OS_LOG_TYPE_ERROR,
+							rejected_category_warning(category, &self.config.category),
+						);
+						self.logger
+					}
+				}
+			}
+		};
+		if logger == self.logger {
+			own
+		} else {
+			(logger, category)
+		}
+	}
+
+	fn release_handles(&self) {
+		if self.config.owns_handle {
+			release(self.logger as *mut _);
+		}
+		// Rejected categories share the logger's own handle, which is released above.
+		for (_, logger) in self.category_loggers.lock().drain() {
+			if logger == self.logger {
+				continue;
+			}
+			release(logger as *mut _);
+		}
+	}
+
+	/// Formats a message according to the configured [`MessageFormat`], with `scope` being the
+	/// spans it happened in.
+	fn format_message<'a, R>(
+		&self,
+		metadata: &Metadata,
+		scope: Option<Scope<'a, R>>,
+		attributes: AttributeMap,
+	) -> String
+	where
+		R: LookupSpan<'a>,
+	{
+		if let Some(format) = &self.config.format_fn {
+			let spans = scope
+				.into_iter()
+				.flat_map(|scope| scope.from_root())
+				.map(|span| {
+					let fields = span
+						.extensions()
+						.get::<Activity>()
+						.map(|activity| activity.attributes.clone())
+						.unwrap_or_default();
+					(span.name(), fields)
+				})
+				.collect();
+			let context = FormatContext {
+				metadata,
+				spans,
+				fields: attributes,
+			};
+			let mut message = String::new();
+			format(&context, &mut message);
+			return message;
+		}
+		match self.config.message_format {
+			MessageFormat::KeyValue => self.format_key_value(metadata, scope, attributes),
+			MessageFormat::SentryBreadcrumb => self.format_sentry_breadcrumb(metadata, attributes),
+		}
+	}
+
+	fn format_key_value<'a, R>(
+		&self,
+		metadata: &Metadata,
+		scope: Option<Scope<'a, R>>,
+		mut attributes: AttributeMap,
+	) -> String
+	where
+		R: LookupSpan<'a>,
+	{
+		let mut message = StringBuilder::default();
+
+		if self.config.with_level {
+			message.append(self.config.level_label(metadata.level()));
+			message.append(" ");
+		}
+
+		if let Some(scope) = scope {
+			let mut has_breadcrumb = false;
+			for span in scope.from_root() {
+				if has_breadcrumb {
+					message.append(":");
+				}
+				has_breadcrumb = true;
+				message.append(span.name());
+
+				// Spans without an activity are only named, as there are no fields to display.
+				let ext = span.extensions();
+				let fields = match ext.get::<Activity>() {
+					Some(activity) => breadcrumb_fields(&activity.attributes),
+					None => Vec::new(),
+				};
+
+				// Only add braces when something is left after skipping the hidden fields.
+				if !fields.is_empty() {
+					message.append("{");
+
+					for (n, (k, v)) in fields.into_iter().enumerate() {
+						if n > 0 {
+							message.append(" ");
+						}
+
+						message.append(k.as_str());
+						message.append("=");
+						message.append(self.display_value(v).as_ref());
+					}
+
+					message.append("}");
+				}
+			}
+			if has_breadcrumb {
+				message.append(self.config.breadcrumb_body_join.as_str());
+			}
+		}
+
+		let has_message = match attributes.remove("message") {
+			Some(value) => {
+				message.append(value);
+				true
+			}
+			None => false,
+		};
+
+		let mut n = 0;
+		for (k, v) in attributes.into_iter() {
+			if is_hidden_field(&k) || !self.config.field_enabled(&k, metadata.level()) {
+				continue;
+			}
+			self.append_field(&mut message, &mut n, has_message, &k, &v);
+		}
+
+		message.string().expect("build string error")
+	}
+
+	/// Appends a field as `key=value`, or in the style set with
+	/// [`OsLogger::flag_fields`], after the message and the `n` fields appended before it.
+	fn append_field(
+		&self,
+		message: &mut StringBuilder,
+		n: &mut usize,
+		has_message: bool,
+		k: &str,
+		v: &str,
+	) {
+		let flag_style = self.config.flag_fields.get(k).copied();
+		let key_only = match flag_style {
+			Some(FlagStyle::KeyOnlyWhenTrue) if v == "false" => return,
+			Some(FlagStyle::KeyOnlyWhenTrue) => v == "true",
+			Some(FlagStyle::KeyValue) | None => false,
+		};
+
+		if *n > 0 {
+			message.append(" ");
+		} else if has_message {
+			message.append("  ");
+		}
+		*n += 1;
+
+		message.append(k);
+		if !key_only {
+			message.append("=");
+			message.append(self.display_value(v).as_ref());
+		}
+	}
+
+	/// Returns a field value as it's displayed in the key-value format, quoted if
+	/// [`OsLogger::quote_values`] asks for it.
+	fn display_value<'v>(&self, value: &'v str) -> Cow<'v, str> {
+		if !self.config.quote_values {
+			return Cow::Borrowed(value);
+		}
+		let needs_quotes = |value: &str| {
+			value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '=' || c == '"')
+		};
+		// Strings are recorded already quoted and escaped, so they only lose their quotes when
+		// they don't need them.
+		let inner = unquoted(value);
+		if inner.len() < value.len() {
+			if needs_quotes(inner) || inner.contains('\\') {
+				return Cow::Borrowed(value);
+			}
+			return Cow::Borrowed(inner);
+		}
+		if needs_quotes(value) {
+			Cow::Owned(format!("{:?}", value))
+		} else {
+			Cow::Borrowed(value)
+		}
+	}
+
+	/// Formats an event as a JSON object matching the schema of Sentry's breadcrumbs.
+	fn format_sentry_breadcrumb(
+		&self,
+		metadata: &Metadata,
+		mut attributes: AttributeMap,
+	) -> String {
+		let level = match *metadata.level() {
+			Level::TRACE | Level::DEBUG => "debug",
+			Level::INFO => "info",
+			Level::WARN => "warning",
+			Level::ERROR => "error",
+		};
+		let mut message = String::from("{\"category\":");
+		json::push_str(&mut message, metadata.target());
+		message.push_str(",\"level\":");
+		json::push_str(&mut message, level);
+		if let Some(value) = attributes.remove("message") {
+			message.push_str(",\"message\":");
+			json::push_str(&mut message, &value);
+		}
+		message.push_str(",\"data\":{");
+		let fields = attributes
+			.iter()
+			.filter(|(k, _)| !is_hidden_field(k) && self.config.field_enabled(k, metadata.level()));
+		for (n, (k, v)) in fields.enumerate() {
+			if n > 0 {
+				message.push(',');
+			}
+			json::push_str(&mut message, k);
+			message.push(':');
+			// Strings are recorded with quotes around them, which JSON already adds. Numbers and
+			// bools aren't, and are kept as they are.
+			if v == "true" || v == "false" || json::is_number(v) {
+				message.push_str(v);
+			} else {
+				json::push_str(&mut message, unquoted(v));
+			}
+		}
+		message.push_str("}}");
+		message
+	}
+
+	/// Counts an event from the given callsite, returning how many times it fired so far.
+	fn callsite_count(&self, metadata: &Metadata) -> u64 {
+		let callsite = metadata.callsite();
+		if let Some(count) = self.callsite_counts.read().get(&callsite) {
+			return count.fetch_add(1, Ordering::Relaxed) + 1;
+		}
+		self.callsite_counts
+			.write()
+			.entry(callsite)
+			.or_default()
+			.fetch_add(1, Ordering::Relaxed)
+			+ 1
+	}
+
+	/// Checks whether `message` is the same as the last one logged from the event's callsite,
+	/// logging a summary of the repeats that were suppressed once they stop or pile up.
+	fn is_repeat(
+		&self,
+		logger: os_log_t,
+		category: &str,
+		metadata: &Metadata,
+		level: os_log_type_t,
+		message: &str,
+	) -> bool {
+		let mut hasher = FnvHasher::default();
+		message.hash(&mut hasher);
+		let hash = hasher.finish();
+
+		let mut last_messages = self.last_messages.lock();
+		if let Some(last) = last_messages.get_mut(&metadata.callsite()) {
+			if last.hash == hash {
+				self.dropped_deduped.fetch_add(1, Ordering::Relaxed);
+				last.repeats += 1;
+				if last.repeats >= DEDUP_SUMMARY_INTERVAL {
+					let summary = last.take_summary();
+					drop(last_messages);
+					self.log_summaries(summary);
+				}
+				return true;
+			}
+		}
+		// Any other message ends the repeats of every callsite, so that none go unreported.
+		let summaries: Vec<_> = last_messages
+			.values_mut()
+			.filter_map(LastMessage::take_summary)
+			.collect();
+		last_messages.insert(
+			metadata.callsite(),
+			LastMessage {
+				hash,
+				repeats: 0,
+				logger,
+				category: category.to_string(),
+				level,
+			},
+		);
+		drop(last_messages);
+		self.log_summaries(summaries);
+		false
+	}
+
+	/// Logs the summaries of repeats that are still pending, e.g. before the logger goes away.
+	fn flush_repeats(&self) {
+		let summaries: Vec<_> = self
+			.last_messages
+			.lock()
+			.values_mut()
+			.filter_map(LastMessage::take_summary)
+			.collect();
+		self.log_summaries(summaries);
+	}
+
+	/// Prefixes the message of a `TRACE` event or span with `[TRACE]` if
+	/// [`TraceHandling::AsDebugWithMarker`] asks for it.
+	fn mark_trace(&self, attributes: &mut AttributeMap) {
+		if self.config.trace_handling != TraceHandling::AsDebugWithMarker {
+			return;
+		}
+		let message = attributes.entry("message".to_string()).or_default();
+		message.insert_str(
+			0,
+			if message.is_empty() {
+				"[TRACE]"
+			} else {
+				"[TRACE] "
+			},
+		);
+	}
+
+	/// Adds the fields set with [`OsLogger::category_fields`] for `category`, unless the event
+	/// has its own.
+	fn add_category_fields(&self, category: &str, attributes: &mut AttributeMap) {
+		if let Some(fields) = self.config.category_fields.get(category) {
+			for (k, v) in fields {
+				attributes.entry(k.clone()).or_insert_with(|| v.clone());
+			}
+		}
+	}
+
+	/// Logs a formatted event or span summary, unless it's a repeat or held back until an error.
+	fn emit(
+		&self,
+		logger: os_log_t,
+		category: &str,
+		metadata: &Metadata,
+		level: os_log_type_t,
+		message: String,
+	) {
+		if self.config.dedup_consecutive
+			&& self.is_repeat(logger, category, metadata, level, &message)
+		{
+			return;
+		}
+		if self.config.buffer_until_error > 0 {
+			if *metadata.level() > Level::WARN {
+				self.buffer(logger, category, level, message);
+				return;
+			}
+			if *metadata.level() == Level::ERROR {
+				self.flush_buffered();
+			}
+		}
+		self.log(logger, category, level, message);
+	}
+
+	fn log_summaries<I>(&self, summaries: I)
+	where
+		I: IntoIterator<Item = (os_log_t, String, os_log_type_t, String)>,
+	{
+		for (logger, category, level, summary) in summaries {
+			self.log(logger, &category, level, summary);
+		}
+	}
+}
+
 impl<S> Layer<S> for OsLogger
 where
 	S: Subscriber + for<'a> LookupSpan<'a>,
 {
-    #[allow(static_mut_refs)]
 	fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
-		let span = ctx.span(id).expect("invalid span, this shouldn't happen");
+		self.config.observe(|| SpanEvent::Opened {
+			id: id.clone(),
+			name: attrs.metadata().name(),
+		});
+		if self.shut_down.load(Ordering::Acquire) {
+			return;
+		}
+		// The registry can't find spans it's already removed, which is only worth a debug message.
+		let span = match ctx.span(id) {
+			Some(span) => span,
+			None => return self.span_not_found("on_new_span", id),
+		};
 		let mut extensions = span.extensions_mut();
 		if extensions.get_mut::<Activity>().is_none() {
-			let mut names = NAMES.lock();
 			let metadata = span.metadata();
-			let parent_activity = match span.parent() {
-				Some(parent) => **parent
-					.extensions()
-					.get::<Activity>()
-					.expect("parent span didn't contain activity wtf"),
-				None => unsafe { &mut _os_activity_current as *mut _ },
+			// A parent without an activity, e.g. one created before this layer was added, is
+			// treated like no parent at all.
+			let parent = span.parent();
+			let parent_extensions = parent.as_ref().map(|parent| parent.extensions());
+			let (parent_activity, parent_depth, parent_category) = match parent_extensions
+				.as_ref()
+				.and_then(|extensions| extensions.get::<Activity>())
+			{
+				Some(parent) => (**parent, parent.depth, parent.category.clone()),
+				None => (current_activity(), 0, None),
 			};
+			let depth = parent_depth + 1;
 			let mut attributes = AttributeMap::default();
-			let mut attr_visitor = FieldVisitor::new(&mut attributes);
+			let mut attr_visitor = FieldVisitor::new(&mut attributes, &self.config);
 			attrs.record(&mut attr_visitor);
-			let name = {
-				let function_name = [metadata.target(), metadata.name()].join("::");
-				let full_name = format!(
-					"{}({})",
-					function_name,
-					attributes
-						.iter()
-						.map(|(k, v)| format!("{}: {}", k, v))
-						.collect::<Vec<_>>()
-						.join(", ")
-				);
-				names.entry(full_name.clone()).or_insert_with(|| {
-					CString::new(full_name).expect("failed to construct C string from span name")
-				})
+			let category = match attributes.get(CATEGORY_FIELD) {
+				Some(category) => Some(unquoted(category).to_string()),
+				None => parent_category,
 			};
-			let activity = unsafe {
-				_os_activity_create(
-					&mut __dso_handle as *mut mach_header as *mut _,
-					name.as_ptr(),
-					parent_activity,
-					os_activity_flag_t_OS_ACTIVITY_FLAG_DEFAULT,
-				)
+			let owns_activity = !matches!(self.config.max_activity_depth, Some(max_depth) if depth > max_depth)
+				&& self
+					.config
+					.activity_filter
+					.as_ref()
+					.is_none_or(|filter| filter(metadata));
+			// Spans without their own activity log to the closest ancestor's instead.
+			let mut owned_name = None;
+			let activity = if owns_activity {
+				let full_name = activity_name(metadata, &attributes);
+				let name = if self.config.cache_span_names {
+					intern_name(full_name)
+				} else {
+					owned_name.insert(c_string(full_name)).as_ptr()
+				};
+				create_activity(name, parent_activity)
+			} else {
+				parent_activity
 			};
 			extensions.insert(Activity {
 				activity,
+				_name: owned_name,
+				owns_activity,
+				depth,
 				attributes,
+				polls: 0,
+				created: self.config.now(),
+				category,
 			});
 		}
 	}
 
 	fn on_event(&self, event: &Event, ctx: Context<S>) {
+		if self.shut_down.load(Ordering::Acquire) {
+			return;
+		}
 		let metadata = event.metadata();
-		let level = match *metadata.level() {
-			Level::TRACE => os_log_type_t_OS_LOG_TYPE_DEBUG,
-			Level::DEBUG => os_log_type_t_OS_LOG_TYPE_DEBUG,
-			Level::INFO => os_log_type_t_OS_LOG_TYPE_INFO,
-			Level::WARN => os_log_type_t_OS_LOG_TYPE_ERROR,
-			Level::ERROR => os_log_type_t_OS_LOG_TYPE_ERROR,
-		};
+		let is_trace = *metadata.level() == Level::TRACE;
+		if is_trace && self.config.trace_handling == TraceHandling::Drop {
+			return;
+		}
+		let level = self.log_type(metadata.level());
+		// Looked up once, as most events outside of spans would otherwise pay for it repeatedly.
+		let span = ctx.event_span(event);
 		let mut attributes = AttributeMap::default();
-		let mut attr_visitor = FieldVisitor::new(&mut attributes);
+		let full_debug =
+			matches!(self.config.full_debug_level, Some(level) if *metadata.level() <= level);
+		let mut attr_visitor =
+			FieldVisitor::new(&mut attributes, &self.config).full_debug(full_debug);
 		event.record(&mut attr_visitor);
 
-		let mut message = StringBuilder::default();
+		// An empty message is treated like none at all, rather than leaving a gap before the fields.
+		if attributes
+			.get("message")
+			.is_some_and(|message| unquoted(message).is_empty())
+		{
+			attributes.remove("message");
+		}
 
-		if let Some(scope) = ctx.event_scope(event) {
-			for span in scope.from_root() {
-				message.append(span.name());
+		// Done before anything is added by this layer, so that only the event's own fields are
+		// deduplicated.
+		if self.config.dedup_shared_keys
+			&& self.config.message_format == MessageFormat::KeyValue
+			&& self.config.format_fn.is_none()
+		{
+			if let Some(span) = &span {
+				for span in span.scope() {
+					let extensions = span.extensions();
+					let span_attributes = match extensions.get::<Activity>() {
+						Some(activity) => &activity.attributes,
+						None => continue,
+					};
+					let shown = span_attributes
+						.iter()
+						.filter(|(k, _)| !is_hidden_field(k) && *k != "message");
+					for (k, v) in shown {
+						if attributes.get(k) == Some(v) {
+							attributes.remove(k);
+						}
+					}
+				}
+			}
+		}
 
-				let ext = span.extensions();
-				let attributes = &ext
-					.get::<Activity>()
-					.expect("will never be `None`")
-					.attributes;
+		if !attributes.contains_key("message") {
+			let fallback = match self.config.fallback_message {
+				FallbackMessage::Empty => None,
+				FallbackMessage::EventName => Some(metadata.name()),
+				FallbackMessage::Target => Some(metadata.target()),
+			};
+			if let Some(fallback) = fallback {
+				attributes.insert("message".to_string(), fallback.to_string());
+			}
+		}
 
-				if !attributes.is_empty() {
-					message.append("{");
+		if is_trace {
+			self.mark_trace(&mut attributes);
+		}
 
-					let mut n = 0;
-					for (k, v) in attributes.iter() {
-						if k.as_str().starts_with("log.") {
-							continue;
-						}
+		if self.config.with_os_log_type {
+			attributes.insert("oslog_type".to_string(), log_type_name(level).to_string());
+		}
 
-						if n > 0 {
-							message.append(" ");
-						}
-						n += 1;
+		if self.config.with_level_numeric {
+			attributes.insert(
+				"level_num".to_string(),
+				level_number(metadata.level()).to_string(),
+			);
+		}
 
-						message.append(k.as_str());
-						message.append("=");
-						message.append(v.as_str());
-					}
+		if self.config.with_subsystem {
+			attributes.insert("subsystem".to_string(), self.config.subsystem.clone());
+		}
 
-					message.append("}");
-				}
+		if self.config.with_event_name {
+			attributes.insert("event".to_string(), metadata.name().to_string());
+		}
+
+		if self.config.with_callsite_count {
+			let count = self.callsite_count(metadata);
+			attributes.insert("count".to_string(), count.to_string());
+		}
 
-				message.append(":");
+		if self.config.with_module_path {
+			if let Some(module_path) = metadata.module_path() {
+				attributes.insert("module".to_string(), module_path.to_string());
 			}
 		}
 
-		if let Some(value) = attributes.remove(&"message".to_string()) {
-			message.append(value);
-			message.append("  ");
+		if self.config.with_queue_label {
+			if let Some(label) = current_queue_label() {
+				attributes.insert("queue".to_string(), label);
+			}
 		}
 
-		let mut n = 0;
-		for (k, v) in attributes.into_iter() {
-			if k.as_str().starts_with("log.") {
-				continue;
+		if self.config.with_mach_tid {
+			if let Some(tid) = mach_tid() {
+				attributes.insert("mach_tid".to_string(), tid.to_string());
 			}
+		}
 
-			if n > 0 {
-				message.append(" ");
+		if self.config.with_span_active {
+			if let Some(span) = &span {
+				// Only this thread's scopes count, as the span being entered elsewhere doesn't make
+				// this event part of it.
+				let id = span.id();
+				let entered =
+					SCOPES.with(|scopes| scopes.borrow().iter().any(|scope| scope.id == id));
+				attributes.insert("span_active".to_string(), entered.to_string());
 			}
-			n += 1;
+		}
 
-			message.append(k);
-			message.append("=");
-			message.append(v);
+		if self.config.with_activity_mismatch {
+			// Spans with no activity in their scope at all can't be logged to the wrong one.
+			let expected = span.as_ref().and_then(|span| {
+				span.extensions()
+					.get::<Activity>()
+					.map(|activity| activity.activity)
+					.filter(|activity| *activity != current_activity())
+			});
+			if let Some(expected) = expected {
+				let current = SCOPES.with(|scopes| {
+					scopes
+						.borrow()
+						.iter()
+						.rev()
+						.find(|scope| scope.state.is_some())
+						.map(|scope| scope.activity)
+				});
+				if current != Some(expected) {
+					attributes.insert("activity_mismatch".to_string(), true.to_string());
+				}
+			}
+		}
+
+		if self.config.with_span_path_hash {
+			if let Some(span) = &span {
+				let mut hasher = FnvHasher::default();
+				for span in span.scope().from_root() {
+					span.name().hash(&mut hasher);
+				}
+				attributes.insert("path_hash".to_string(), hasher.finish().to_string());
+			}
 		}
 
-		let message = CString::new(message.string().expect("build string error"))
-			.expect("failed to convert formatted message to a C string");
-		unsafe { wrapped_os_log_with_type(self.logger, level, message.as_ptr()) };
+		for composite in &self.config.composite_fields {
+			let mut values = Vec::with_capacity(composite.sources.len());
+			for source in &composite.sources {
+				let value = match attributes.get(source) {
+					Some(value) => Some(unquoted(value).to_string()),
+					None => span.as_ref().and_then(|span| {
+						span.scope().find_map(|span| {
+							span.extensions()
+								.get::<Activity>()
+								.and_then(|activity| activity.attributes.get(source))
+								.map(|value| unquoted(value).to_string())
+						})
+					}),
+				};
+				match value {
+					Some(value) => values.push(value),
+					None if composite.require_all => break,
+					None => {}
+				}
+			}
+			let complete = !composite.require_all || values.len() == composite.sources.len();
+			if complete && !values.is_empty() {
+				attributes.insert(
+					composite.name.clone(),
+					values.join(composite.separator.as_str()),
+				);
+			}
+		}
+
+		let category = match attributes.get(CATEGORY_FIELD) {
+			Some(category) => Some(unquoted(category).to_string()),
+			None => span.as_ref().and_then(|span| {
+				span.extensions()
+					.get::<Activity>()
+					.and_then(|activity| activity.category.clone())
+			}),
+		};
+		let (logger, category) = self.category_logger(category.as_deref());
+		self.add_category_fields(category, &mut attributes);
+
+		let depth = match &span {
+			Some(span) if self.config.indent_by_depth && self.config.format_fn.is_none() => span
+				.extensions()
+				.get::<Activity>()
+				.map_or(0, |activity| activity.depth),
+			_ => 0,
+		};
+		let scope = span.map(|span| span.scope());
+		let mut message = self.format_message(metadata, scope, attributes);
+		if depth > 0 {
+			message.insert_str(0, &"  ".repeat(depth));
+		}
+		self.emit(logger, category, metadata, level, message);
 	}
 
 	fn on_enter(&self, id: &Id, ctx: Context<S>) {
-		let span = ctx.span(id).expect("invalid span, this shouldn't happen");
+		self.config
+			.observe(|| SpanEvent::Entered { id: id.clone() });
+		let span = match ctx.span(id) {
+			Some(span) => span,
+			None => return self.span_not_found("on_enter", id),
+		};
 		let mut extensions = span.extensions_mut();
-		let activity = extensions
-			.get_mut::<Activity>()
-			.expect("span didn't contain activity wtf");
-		unsafe {
-			os_activity_scope_enter(**activity, &self.state as *const _ as *mut _);
+		// Spans created after `shutdown` don't have an activity, and as the os_log handles are
+		// released by then, skipping them can't be logged.
+		if let Some(activity) = extensions.get_mut::<Activity>() {
+			activity.polls += 1;
+			// Without an activity of its own, the ancestor whose activity this is has already
+			// entered it.
+			let state = if activity.owns_activity {
+				let mut state: Box<os_activity_scope_state_s> =
+					Box::new(unsafe { std::mem::zeroed() });
+				unsafe {
+					os_activity_scope_enter(**activity, &mut *state);
+				}
+				Some(state)
+			} else {
+				None
+			};
+			SCOPES.with(|scopes| {
+				scopes.borrow_mut().push(EnteredScope {
+					id: id.clone(),
+					name: span.name(),
+					activity: **activity,
+					state,
+				})
+			});
 		}
 	}
 
-	fn on_exit(&self, _id: &Id, _ctx: Context<S>) {
-		unsafe {
-			os_activity_scope_leave(&self.state as *const _ as *mut _);
+	fn on_exit(&self, id: &Id, ctx: Context<S>) {
+		self.config.observe(|| SpanEvent::Exited { id: id.clone() });
+		let span = match ctx.span(id) {
+			Some(span) => span,
+			None => return self.span_not_found("on_exit", id),
+		};
+		// Like in `on_enter`, only spans created after `shutdown` lack an activity, and there's
+		// nothing left to log that to.
+		if span.extensions().get::<Activity>().is_none() {
+			return;
+		}
+		let (state, entered, innermost) = SCOPES.with(|scopes| {
+			let mut scopes = scopes.borrow_mut();
+			let position = scopes.iter().rposition(|scope| scope.id == *id);
+			let innermost = position.is_none_or(|position| position + 1 == scopes.len());
+			let state = position.and_then(|position| scopes.remove(position).state);
+			(state, position.is_some(), innermost)
+		});
+		// Not having a scope means the span was entered before this logger was around.
+		if let Some(mut state) = state {
+			unsafe {
+				os_activity_scope_leave(&mut *state);
+			}
+		}
+		// Checked once nothing is borrowed anymore, so that the span can still be closed after
+		// a failed check.
+		if self.config.validate_scope_balance {
+			debug_assert!(
+				entered,
+				"span `{}` exited without being entered on this thread",
+				span.name()
+			);
+			debug_assert!(
+				innermost,
+				"span `{}` exited before the spans entered after it",
+				span.name()
+			);
 		}
 	}
 
 	fn on_close(&self, id: Id, ctx: Context<S>) {
-		let span = ctx.span(&id).expect("invalid span, this shouldn't happen");
-		let mut extensions = span.extensions_mut();
-		extensions
-			.remove::<Activity>()
-			.expect("span didn't contain activity wtf");
+		self.config.observe(|| SpanEvent::Closed { id: id.clone() });
+		let span = match ctx.span(&id) {
+			Some(span) => span,
+			None => return self.span_not_found("on_close", &id),
+		};
+		let activity = span.extensions_mut().remove::<Activity>();
+		if let Some(activity) = activity {
+			let metadata = span.metadata();
+			let is_trace = *metadata.level() == Level::TRACE;
+			if self.config.emit_span_summary_on_close
+				&& !self.shut_down.load(Ordering::Acquire)
+				&& !(is_trace && self.config.trace_handling == TraceHandling::Drop)
+			{
+				let mut attributes = activity.attributes.clone();
+				attributes.insert(
+					"elapsed".to_string(),
+					format!(
+						"{:?}",
+						self.config
+							.now()
+							.saturating_duration_since(activity.created)
+					),
+				);
+				if self.config.with_poll_count {
+					attributes.insert("polls".to_string(), activity.polls.to_string());
+				}
+				attributes.insert("message".to_string(), span.name().to_string());
+				if is_trace {
+					self.mark_trace(&mut attributes);
+				}
+				let (logger, category) = self.category_logger(activity.category.as_deref());
+				self.add_category_fields(category, &mut attributes);
+				let message = self.format_message(
+					metadata,
+					span.parent().map(|parent| parent.scope()),
+					attributes,
+				);
+				self.emit(
+					logger,
+					category,
+					metadata,
+					self.log_type(metadata.level()),
+					message,
+				);
+			}
+		}
 	}
 }
 
 impl Drop for OsLogger {
 	fn drop(&mut self) {
-		unsafe {
-			os_release(self.logger as *mut _);
+		if !*self.shut_down.get_mut() {
+			self.flush_repeats();
+			self.release_handles();
+			self.discard_buffered();
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::sink::Sink;
+	use std::sync::Arc;
+	use tracing::{debug, error, info, info_span, trace, warn};
+	use tracing_subscriber::layer::SubscriberExt;
+
+	thread_local! {
+		/// What this thread passed to `os_release`, so tests can check what gets released.
+		pub(super) static RELEASED: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+	}
+
+	/// Keeps the category and message of everything a logger would send to os_log.
+	#[derive(Clone, Default)]
+	struct Capture(Arc<Mutex<Vec<(String, String)>>>);
+
+	impl Sink for Capture {
+		fn emit(&self, record: &LogRecord) {
+			self.0
+				.lock()
+				.push((record.category.to_string(), record.message.to_string()));
+		}
+	}
+
+	fn logger() -> OsLogger {
+		OsLogger::new("com.example.test", "test")
+	}
+
+	/// Runs `f` with `logger` as the only layer, returning the categories and messages logged.
+	fn capture_records<F: FnOnce()>(logger: OsLogger, f: F) -> Vec<(String, String)> {
+		let capture = Capture::default();
+		let subscriber = tracing_subscriber::registry().with(logger.with_sink(capture.clone()));
+		tracing::subscriber::with_default(subscriber, f);
+		let records = capture.0.lock().clone();
+		records
+	}
+
+	/// Calls `f` with the logger of the current subscriber.
+	fn with_logger<F: FnMut(&OsLogger)>(mut f: F) {
+		tracing::dispatcher::get_default(|dispatch| {
+			f(dispatch
+				.downcast_ref::<OsLogger>()
+				.expect("the current subscriber has no OsLogger"))
+		});
+	}
+
+	/// A clock that's stuck at the moment it was created.
+	struct Frozen(Instant);
+
+	impl Frozen {
+		fn new() -> Self {
+			Self(Instant::now())
+		}
+	}
+
+	impl crate::Clock for Frozen {
+		fn now(&self) -> Instant {
+			self.0
+		}
+	}
+
+	/// Runs `f` with `logger` as the only layer, returning the messages logged.
+	fn capture<F: FnOnce()>(logger: OsLogger, f: F) -> Vec<String> {
+		capture_records(logger, f)
+			.into_iter()
+			.map(|(_, message)| message)
+			.collect()
+	}
+
+	#[test]
+	fn every_sink_gets_the_category() {
+		let first = Capture::default();
+		let records = capture_records(logger().with_sink(first.clone()), || {
+			info!(os_log.category = "http", "request");
+			error!("failed");
+		});
+		let expected = [
+			("http".to_string(), "request".to_string()),
+			("test".to_string(), "failed".to_string()),
+		];
+		assert_eq!(records, expected);
+		assert_eq!(*first.0.lock(), expected);
+	}
+
+	#[test]
+	fn dump_lists_spans_past_max_activity_depth() {
+		let messages = capture(logger().max_activity_depth(1), || {
+			let outer = info_span!("outer");
+			let _outer = outer.enter();
+			let inner = info_span!("inner");
+			let _inner = inner.enter();
+			with_logger(OsLogger::dump_active_spans);
+		});
+		let message = &messages[0];
+		assert!(message.starts_with("active spans: outer("), "{}", message);
+		assert!(message.contains(") > inner("), "{}", message);
+	}
+
+	#[test]
+	fn activity_mismatch_only_for_events_outside_their_activity() {
+		let messages = capture(logger().with_activity_mismatch(true), || {
+			let span = info_span!("sp");
+			info!(parent: &span, "outside");
+			let _entered = span.enter();
+			info!("inside");
+		});
+		assert_eq!(
+			messages,
+			["sp:outside  activity_mismatch=true", "sp:inside"]
+		);
+	}
+
+	#[test]
+	fn no_activity_mismatch_without_an_activity_in_scope() {
+		let filtered = logger()
+			.with_activity_mismatch(true)
+			.activity_filter(|_| false);
+		let no_depth = logger().with_activity_mismatch(true).max_activity_depth(0);
+		for logger in [filtered, no_depth] {
+			let messages = capture(logger, || {
+				let span = info_span!("sp", a = 1);
+				let _entered = span.enter();
+				info!("in root");
+			});
+			assert_eq!(messages, ["sp{a=1}:in root"]);
+		}
+	}
+
+	#[test]
+	fn dedup_shared_keys_only_drops_equal_event_fields() {
+		let messages = capture(logger().dedup_shared_keys(true), || {
+			let span = info_span!("sp", a = 1, b = 2, message = "same");
+			let _entered = span.enter();
+			info!(a = 1, b = 3, message = "same");
+		});
+		assert_eq!(messages, ["sp{a=1 b=2 message=\"same\"}:\"same\"  b=3"]);
+	}
+
+	#[test]
+	fn rejected_category_falls_back_with_one_warning() {
+		let records = capture_records(logger(), || {
+			info!(os_log.category = "a\0b", "first");
+			info!(os_log.category = "a\0b", "second");
+		});
+		let warning = rejected_category_warning("a\0b", "test");
+		assert_eq!(
+			records,
+			[
+				("test".to_string(), warning),
+				("test".to_string(), "first".to_string()),
+				("test".to_string(), "second".to_string()),
+			]
+		);
+	}
+
+	#[test]
+	fn format_fn_gets_every_field_unindented() {
+		let logger = logger()
+			.dedup_shared_keys(true)
+			.indent_by_depth(true)
+			.format_fn(|context, output| {
+				let fields: Vec<_> = context.fields().keys().map(String::as_str).collect();
+				output.push_str(&fields.join(","));
+			});
+		let messages = capture(logger, || {
+			let span = info_span!("sp", a = 1);
+			let _entered = span.enter();
+			info!(a = 1, b = 2, "msg");
+		});
+		assert_eq!(messages, ["a,b,message"]);
+	}
+
+	#[test]
+	fn buffered_events_wait_for_an_error() {
+		let records = Arc::new(Mutex::new(Vec::new()));
+		let logger = logger()
+			.buffer_until_error(2)
+			.with_sink(Capture(records.clone()));
+		let subscriber = tracing_subscriber::registry().with(logger);
+		tracing::subscriber::with_default(subscriber, || {
+			info!("first");
+			info!("second");
+			info!("third");
+			assert!(records.lock().is_empty());
+			error!("failed");
+		});
+		let messages: Vec<_> = records
+			.lock()
+			.drain(..)
+			.map(|(_, message)| message)
+			.collect();
+		assert_eq!(messages, ["second", "third", "failed"]);
+	}
+
+	#[test]
+	fn quote_values_escapes_strings_once() {
+		let messages = capture(logger().quote_values(true), || {
+			info!(
+				a = ?"a\"b",
+				b = "a\"b",
+				c = "x\ny",
+				d = %"a\tb",
+				e = "plain",
+				"msg"
+			);
+		});
+		assert_eq!(
+			messages,
+			[r#"msg  a="a\"b" b="a\"b" c="x\ny" d="a\tb" e=plain"#]
+		);
+	}
+
+	#[test]
+	fn force_log_formats_fields_like_events() {
+		let logger = logger()
+			.with_level(true)
+			.max_field_bytes(3)
+			.quote_values(true);
+		let messages = capture(logger, || {
+			with_logger(|logger| {
+				logger.force_log(Level::TRACE, "", &[("a", "1"), ("b", "x y"), ("c", "long")]);
+			});
+		});
+		assert_eq!(messages, [r#"TRACE a=1 b="x y" c=lon…"#]);
+	}
+
+	#[test]
+	fn messages_keep_their_quotes() {
+		let messages = capture(logger(), || {
+			info!(c = ?'x', "'x'");
+		});
+		assert_eq!(messages, ["'x'  c=x"]);
+	}
+	#[test]
+	fn spans_with_only_hidden_fields_have_no_braces() {
+		let messages = capture(logger(), || {
+			let span = info_span!("sp", log.target = "app");
+			let _entered = span.enter();
+			info!("msg");
+		});
+		assert_eq!(messages, ["sp:msg"]);
+	}
+	#[test]
+	fn empty_messages_are_absent() {
+		let messages = capture(logger(), || {
+			info!(message = "", a = 1);
+		});
+		assert_eq!(messages, ["a=1"]);
+	}
+
+	#[test]
+	fn empty_messages_get_the_fallback() {
+		let messages = capture(logger().fallback_message(FallbackMessage::Target), || {
+			info!(message = "", a = 1);
+		});
+		assert_eq!(messages, [format!("{}  a=1", module_path!())]);
+	}
+
+	#[test]
+	fn trace_as_debug() {
+		let messages = capture(logger().with_os_log_type(true), || {
+			trace!("fine");
+			debug!("coarse");
+		});
+		assert_eq!(
+			messages,
+			["fine  oslog_type=debug", "coarse  oslog_type=debug"]
+		);
+	}
+
+	#[test]
+	fn trace_dropped() {
+		let logger = logger().trace_handling(TraceHandling::Drop);
+		let messages = capture(logger, || {
+			trace!("fine");
+			debug!("coarse");
+		});
+		assert_eq!(messages, ["coarse"]);
+	}
+
+	#[test]
+	fn trace_as_debug_with_marker() {
+		let logger = logger()
+			.trace_handling(TraceHandling::AsDebugWithMarker)
+			.with_os_log_type(true);
+		let messages = capture(logger, || {
+			trace!("fine");
+			trace!(a = 1);
+			debug!("coarse");
+		});
+		assert_eq!(
+			messages,
+			[
+				"[TRACE] fine  oslog_type=debug",
+				"[TRACE]  a=1 oslog_type=debug",
+				"coarse  oslog_type=debug",
+			]
+		);
+	}
+	#[test]
+	fn flag_fields_show_only_true_keys() {
+		let logger = logger().flag_fields(&["retrying", "cached"], FlagStyle::KeyOnlyWhenTrue);
+		let messages = capture(logger, || {
+			info!(retrying = true, cached = false, "fetch");
+			info!(retrying = false, cached = true, "fetch");
+			info!(retrying = "maybe", "fetch");
+		});
+		assert_eq!(
+			messages,
+			[
+				"fetch  retrying",
+				"fetch  cached",
+				"fetch  retrying=\"maybe\""
+			]
+		);
+	}
+	#[test]
+	fn breadcrumb_body_join_only_follows_a_breadcrumb() {
+		for (logger, joined) in [
+			(logger(), "sp:inside"),
+			(logger().breadcrumb_body_join(" | "), "sp | inside"),
+		] {
+			let messages = capture(logger, || {
+				info!("outside");
+				let span = info_span!("sp");
+				let _entered = span.enter();
+				info!("inside");
+			});
+			assert_eq!(messages, ["outside", joined]);
+		}
+	}
+	#[test]
+	fn balanced_scopes_pass_validation() {
+		let messages = capture(logger().validate_scope_balance(true), || {
+			let outer = info_span!("outer");
+			let _outer = outer.enter();
+			for _ in 0..2 {
+				let inner = info_span!("inner");
+				let _inner = inner.enter();
+				info!("msg");
+			}
+		});
+		assert_eq!(messages, ["outer:inner:msg", "outer:inner:msg"]);
+	}
+
+	#[test]
+	#[cfg(debug_assertions)]
+	#[should_panic(expected = "span `outer` exited before the spans entered after it")]
+	fn out_of_order_exits_fail_validation() {
+		capture(logger().validate_scope_balance(true), || {
+			let outer = info_span!("outer");
+			let inner = info_span!("inner");
+			let outer_entered = outer.enter();
+			let _inner_entered = inner.enter();
+			drop(outer_entered);
+		});
+	}
+
+	#[test]
+	#[cfg(debug_assertions)]
+	#[should_panic(expected = "span `sp` exited without being entered on this thread")]
+	fn exits_without_enters_fail_validation() {
+		capture(logger().validate_scope_balance(true), || {
+			let span = info_span!("sp");
+			let id = span.id().expect("the span is disabled");
+			tracing::dispatcher::get_default(|dispatch| dispatch.exit(&id));
+		});
+	}
+	#[test]
+	fn uncached_span_names_stay_out_of_the_cache() {
+		capture(logger().cache_span_names(false), || {
+			let span = info_span!("uncached_span");
+			let _entered = span.enter();
+			info!("msg");
+		});
+		let cached = |name: &str| NAMES.lock().keys().any(|key| key.contains(name));
+		assert!(!cached("uncached_span"));
+
+		capture(logger(), || {
+			let _span = info_span!("interned_span");
+		});
+		assert!(cached("interned_span"));
+	}
+	#[test]
+	fn composite_fields_join_span_and_event_fields() {
+		let logger = logger()
+			.composite_field("corr", &["user_id", "request_id"], ":")
+			.partial_composite_field("any", &["user_id", "missing"], ":");
+		let messages = capture(logger, || {
+			info!(request_id = 7, "outside");
+			let span = info_span!("sp", user_id = 42);
+			let _entered = span.enter();
+			info!(request_id = 7, "inside");
+		});
+		assert_eq!(
+			messages,
+			[
+				"outside  request_id=7",
+				"sp{user_id=42}:inside  any=42 corr=42:7 request_id=7"
+			]
+		);
+	}
+	#[test]
+	fn spans_survive_concurrent_creation_and_closing() {
+		const THREADS: usize = 8;
+		const SPANS: usize = 200;
+		let messages = capture(logger().emit_span_summary_on_close(true), || {
+			let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+			let (sender, receiver) = std::sync::mpsc::channel();
+			let threads: Vec<_> = (0..THREADS)
+				.map(|thread| {
+					let dispatch = dispatch.clone();
+					let sender = sender.clone();
+					std::thread::spawn(move || {
+						tracing::dispatcher::with_default(&dispatch, || {
+							let parent = info_span!("parent", thread);
+							let _parent = parent.enter();
+							for n in 0..SPANS {
+								let span = info_span!("child", n);
+								span.in_scope(|| info!("event"));
+								// Every other span is closed on the main thread instead.
+								if n % 2 == 0 {
+									sender.send(span).expect("the receiver is gone");
+								}
+							}
+						})
+					})
+				})
+				.collect();
+			drop(sender);
+			let moved: Vec<_> = receiver.into_iter().collect();
+			for thread in threads {
+				thread.join().expect("a span thread panicked");
+			}
+			drop(moved);
+		});
+		let events = messages
+			.iter()
+			.filter(|message| message.ends_with(":event"))
+			.count();
+		let summaries = messages.len() - events;
+		assert_eq!(events, THREADS * SPANS);
+		assert_eq!(summaries, THREADS * (SPANS + 1));
+	}
+	#[test]
+	fn shared_handles_are_not_released() {
+		let shared = OsLogger::new("com.example.test", "shared").owns_handle(false);
+		let shared_handle = shared.logger as usize;
+		let (http_handle, _) = shared.category_logger(Some("http"));
+		let http_handle = http_handle as usize;
+		drop(shared);
+		let owned = OsLogger::new("com.example.test", "owned");
+		let owned_handle = owned.logger as usize;
+		drop(owned);
+
+		let released = RELEASED.with(|released| released.borrow().clone());
+		assert!(!released.contains(&shared_handle));
+		assert!(released.contains(&http_handle));
+		assert!(released.contains(&owned_handle));
+	}
+	#[test]
+	fn rejected_own_category_falls_back_to_default() {
+		let logger = OsLogger::new("com.example.test", "a\0b");
+		assert_eq!(logger.config.category, FALLBACK_CATEGORY);
+		let records = capture_records(logger, || info!("msg"));
+		assert_eq!(
+			records,
+			[(FALLBACK_CATEGORY.to_string(), "msg".to_string())]
+		);
+	}
+	#[test]
+	fn labels_the_activity_of_a_span() {
+		let messages = capture(logger(), || {
+			OsLogger::label_current_activity("outside");
+			let span = info_span!("sp");
+			let _entered = span.enter();
+			OsLogger::label_current_activity("checkout");
+			info!("msg");
+		});
+		assert_eq!(messages, ["sp:msg"]);
+	}
+
+	#[test]
+	fn shutdown_releases_handles_and_clears_caches() {
+		let logger = logger().dedup_consecutive(true).with_callsite_count(true);
+		let handle = logger.logger as usize;
+		let (http, _) = logger.category_logger(Some("http"));
+		let http = http as usize;
+		let subscriber = tracing_subscriber::registry().with(logger);
+		tracing::subscriber::with_default(subscriber, || {
+			info!("msg");
+			with_logger(|logger| {
+				logger.shutdown();
+				logger.shutdown();
+				assert!(logger.category_loggers.lock().is_empty());
+				assert!(logger.last_messages.lock().is_empty());
+				assert!(logger.callsite_counts.read().is_empty());
+			});
+		});
+		let released = RELEASED.with(|released| released.borrow().clone());
+		assert_eq!(
+			released
+				.iter()
+				.filter(|&&released| released == handle)
+				.count(),
+			1
+		);
+		assert_eq!(
+			released
+				.iter()
+				.filter(|&&released| released == http)
+				.count(),
+			1
+		);
+	}
+
+	#[test]
+	fn repeats_are_summarized() {
+		let messages = capture(logger().dedup_consecutive(true), || {
+			for _ in 0..100 {
+				info!("same");
+			}
+			with_logger(|logger| assert_eq!(logger.dropped_deduped(), 99));
+		});
+		assert_eq!(messages, ["same", "last message repeated 99 times"]);
+	}
+
+	#[test]
+	fn other_messages_end_the_repeats() {
+		let messages = capture(logger().dedup_consecutive(true), || {
+			for _ in 0..250 {
+				info!("same");
+			}
+			info!("different");
+			for _ in 0..2 {
+				info!("again");
+			}
+		});
+		assert_eq!(
+			messages,
+			[
+				"same",
+				"last message repeated 100 times",
+				"last message repeated 100 times",
+				"last message repeated 49 times",
+				"different",
+				"again",
+				"last message repeated 1 times",
+			]
+		);
+	}
+	#[test]
+	fn max_field_bytes_caps_fields_but_not_messages() {
+		let messages = capture(logger().max_field_bytes(8), || {
+			info!(s = "a fairly long value", "a fairly long message body");
+		});
+		assert_eq!(messages, ["a fairly long message body  s=\"a fairly…\""]);
+	}
+
+	#[test]
+	fn max_field_bytes_bounds_activity_names() {
+		let huge = "x".repeat(10 * 1024);
+		capture(logger().max_field_bytes(64), || {
+			let _span = info_span!("huge_field_span", huge = huge.as_str());
+		});
+		let names = NAMES.lock();
+		let name = names
+			.keys()
+			.find(|name| name.contains("huge_field_span"))
+			.expect("the span's activity name wasn't cached");
+		assert!(name.len() < 256, "{} bytes", name.len());
+	}
+	#[test]
+	fn spans_pass_their_category_on() {
+		let records = capture_records(logger(), || {
+			let parent = info_span!("parent", os_log.category = "http");
+			let _parent = parent.enter();
+			info!("inherited");
+			info!(os_log.category = "db", "overridden");
+			let child = info_span!("child");
+			let _child = child.enter();
+			info!("nested");
+		});
+		let categories: Vec<_> = records
+			.iter()
+			.map(|(category, _)| category.as_str())
+			.collect();
+		assert_eq!(categories, ["http", "db", "http"]);
+	}
+	#[test]
+	fn span_summaries_time_with_the_clock() {
+		let logger = logger()
+			.emit_span_summary_on_close(true)
+			.clock(Frozen::new());
+		let messages = capture(logger, || {
+			let _span = info_span!("sp");
+		});
+		assert_eq!(messages, ["sp  elapsed=0ns"]);
+	}
+	#[test]
+	fn span_summaries_list_the_fields() {
+		let logger = logger()
+			.emit_span_summary_on_close(true)
+			.clock(Frozen::new());
+		let messages = capture(logger, || {
+			let parent = info_span!("parent", user = "ann");
+			let _parent = parent.enter();
+			let _child = info_span!("child", n = 1);
+		});
+		assert_eq!(
+			messages,
+			[
+				"parent{user=\"ann\"}:child  elapsed=0ns n=1",
+				"parent  elapsed=0ns user=\"ann\"",
+			]
+		);
+	}
+
+	#[test]
+	fn span_summaries_are_filtered_like_events() {
+		let logger = logger()
+			.emit_span_summary_on_close(true)
+			.trace_handling(TraceHandling::Drop)
+			.buffer_until_error(10)
+			.category_fields("http", &[("component", "http")])
+			.clock(Frozen::new());
+		let records = capture_records(logger, || {
+			drop(tracing::trace_span!("dropped"));
+			drop(info_span!("held", os_log.category = "http"));
+			error!("failed");
+		});
+		assert_eq!(
+			records,
+			[
+				(
+					"http".to_string(),
+					"held  component=http elapsed=0ns".to_string()
+				),
+				("test".to_string(), "failed".to_string()),
+			]
+		);
+	}
+	#[test]
+	fn sentry_breadcrumbs_keep_value_types() {
+		let logger = logger().message_format(MessageFormat::SentryBreadcrumb);
+		let messages = capture(logger, || {
+			info!(n = 5, x = 1.5, b = true, s = "5", "done");
+		});
+		assert_eq!(
+			messages,
+			[format!(
+				r#"{{"category":"{}","level":"info","message":"done","data":{{"b":true,"n":5,"s":"5","x":1.5}}}}"#,
+				module_path!()
+			)]
+		);
+	}
+	#[test]
+	fn custom_level_labels() {
+		fn short(level: &Level) -> &'static str {
+			match *level {
+				Level::ERROR => "ERR",
+				Level::WARN => "WRN",
+				_ => "---",
+			}
+		}
+
+		let messages = capture(logger().with_level(true).level_label(short), || {
+			error!("failed");
+			warn!("slow");
+			info!("fine");
+		});
+		assert_eq!(messages, ["ERR failed", "WRN slow", "--- fine"]);
+	}
+	#[test]
+	fn fields_can_be_limited_to_verbose_levels() {
+		let logger = logger().include_field_at_level("sql", Level::DEBUG);
+		let messages = capture(logger, || {
+			debug!(sql = "select 1", rows = 1, "query");
+			info!(sql = "select 1", rows = 1, "query");
+		});
+		assert_eq!(
+			messages,
+			["query  rows=1 sql=\"select 1\"", "query  rows=1"]
+		);
+	}
+	#[test]
+	fn span_observer_sees_a_balanced_lifecycle() {
+		let seen = Arc::new(Mutex::new(Vec::new()));
+		let observed = seen.clone();
+		let logger = logger().set_span_observer(move |event| {
+			let event = match event {
+				SpanEvent::Opened { id, name } => format!("opened {} {}", name, id.into_u64()),
+				SpanEvent::Entered { id } => format!("entered {}", id.into_u64()),
+				SpanEvent::Exited { id } => format!("exited {}", id.into_u64()),
+				SpanEvent::Closed { id } => format!("closed {}", id.into_u64()),
+			};
+			observed.lock().push(event);
+		});
+		let mut id = 0;
+		capture(logger, || {
+			let span = info_span!("sp");
+			id = span.id().expect("the span is disabled").into_u64();
+			span.in_scope(|| {});
+		});
+		let expected =
+			["opened sp", "entered", "exited", "closed"].map(|event| format!("{} {}", event, id));
+		assert_eq!(*seen.lock(), expected);
+	}
+	#[test]
+	fn span_active_only_inside_an_enter_guard_on_this_thread() {
+		let messages = capture(logger().with_span_active(true), || {
+			let span = info_span!("sp");
+			info!(parent: &span, "outside");
+			let _entered = span.enter();
+			info!("inside");
+			let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+			let span = span.clone();
+			std::thread::spawn(move || {
+				tracing::dispatcher::with_default(&dispatch, || info!(parent: &span, "elsewhere"))
+			})
+			.join()
+			.expect("the thread panicked");
+		});
+		assert_eq!(
+			messages,
+			[
+				"sp:outside  span_active=false",
+				"sp:inside  span_active=true",
+				"sp:elsewhere  span_active=false",
+			]
+		);
+	}
+	#[test]
+	fn span_summaries_count_polls() {
+		let logger = logger()
+			.emit_span_summary_on_close(true)
+			.with_poll_count(true)
+			.clock(Frozen::new());
+		let messages = capture(logger, || {
+			let span = info_span!("task");
+			for _ in 0..3 {
+				span.in_scope(|| {});
+			}
+		});
+		assert_eq!(messages, ["task  elapsed=0ns polls=3"]);
+	}
+	#[test]
+	fn deeper_events_are_indented_more() {
+		let messages = capture(logger().indent_by_depth(true), || {
+			info!("top");
+			let outer = info_span!("outer");
+			let _outer = outer.enter();
+			info!("one");
+			let inner = info_span!("inner");
+			let _inner = inner.enter();
+			info!("two");
+		});
+		assert_eq!(messages, ["top", "  outer:one", "    outer:inner:two"]);
+	}
+	#[test]
+	fn full_debug_values_at_error() {
+		let logger = logger()
+			.max_field_bytes(8)
+			.full_debug_at_level(Level::ERROR);
+		let value = vec![1234; 4];
+		let messages = capture(logger, || {
+			info!(v = ?value, "info");
+			error!(v = ?value, "error");
+		});
+		assert_eq!(
+			messages,
+			["info  v=[1234, 1…", "error  v=[1234, 1234, 1234, 1234]"]
+		);
+	}
+	#[test]
+	fn callsites_count_separately() {
+		let messages = capture(logger().with_callsite_count(true), || {
+			for _ in 0..3 {
+				info!("first");
+			}
+			info!("second");
+		});
+		assert_eq!(
+			messages,
+			[
+				"first  count=1",
+				"first  count=2",
+				"first  count=3",
+				"second  count=1"
+			]
+		);
+	}
+	#[test]
+	fn recent_logs_keep_the_last_messages() {
+		capture(logger().recent_logs_capacity(3), || {
+			for n in 0..5 {
+				info!("message {}", n);
+			}
+			with_logger(|logger| {
+				assert_eq!(
+					logger.recent_logs(),
+					["message 2", "message 3", "message 4"]
+				);
+			});
+		});
+	}
+	#[test]
+	fn span_path_hashes_group_events_by_path() {
+		let messages = capture(logger().with_span_path_hash(true), || {
+			for name in ["a", "b"] {
+				let outer = info_span!("outer");
+				let _outer = outer.enter();
+				let inner = match name {
+					"a" => info_span!("a"),
+					_ => info_span!("b"),
+				};
+				let _inner = inner.enter();
+				info!("first");
+				info!("second");
+			}
+		});
+		let hashes: Vec<_> = messages
+			.iter()
+			.map(|message| message.split("path_hash=").nth(1).expect("no path_hash"))
+			.collect();
+		assert_eq!(hashes[0], hashes[1]);
+		assert_eq!(hashes[2], hashes[3]);
+		assert_ne!(hashes[0], hashes[2]);
+	}
+	#[test]
+	fn field_order_puts_listed_fields_first() {
+		let messages = capture(logger(), || {
+			let span = info_span!(
+				"request",
+				path = "/",
+				method = "GET",
+				status = 200,
+				os_log.field_order = "status,method"
+			);
+			let _entered = span.enter();
+			info!("done");
+		});
+		assert_eq!(
+			messages,
+			["request{status=200 method=\"GET\" path=\"/\"}:done"]
+		);
+	}
+	#[test]
+	fn category_fields_only_in_their_category() {
+		let logger = logger().category_fields("http", &[("component", "http")]);
+		let records = capture_records(logger, || {
+			info!(os_log.category = "http", "request");
+			info!(os_log.category = "db", "query");
+			info!("plain");
+		});
+		assert_eq!(
+			records,
+			[
+				("http".to_string(), "request  component=http".to_string()),
+				("db".to_string(), "query".to_string()),
+				("test".to_string(), "plain".to_string()),
+			]
+		);
+	}
+	#[test]
+	fn level_table_maps_each_level() {
+		let logger = logger().with_os_log_type(true).with_level_table([
+			LogType::Fault,
+			LogType::Default,
+			LogType::Info,
+			LogType::Debug,
+			LogType::Error,
+		]);
+		let messages = capture(logger, || {
+			trace!("t");
+			debug!("d");
+			info!("i");
+			warn!("w");
+			error!("e");
+		});
+		assert_eq!(
+			messages,
+			[
+				"t  oslog_type=fault",
+				"d  oslog_type=default",
+				"i  oslog_type=info",
+				"w  oslog_type=debug",
+				"e  oslog_type=error",
+			]
+		);
+	}
+	#[test]
+	fn each_fallback_message_source() {
+		let mut line = 0;
+		let sources = [
+			FallbackMessage::Empty,
+			FallbackMessage::EventName,
+			FallbackMessage::Target,
+		];
+		let messages: Vec<_> = sources
+			.iter()
+			.flat_map(|&source| {
+				capture(logger().fallback_message(source), || {
+					line = line!() + 1;
+					info!(a = 1);
+				})
+			})
+			.collect();
+		assert_eq!(
+			messages,
+			[
+				"a=1".to_string(),
+				format!("event {}:{}  a=1", file!(), line),
+				format!("{}  a=1", module_path!()),
+			]
+		);
+	}
+
+	#[test]
+	fn trailing_newline_is_trimmed() {
+		let messages = capture(logger(), || info!("line\n"));
+		assert_eq!(messages, ["line"]);
+		let messages = capture(logger().trim_trailing_newline(false), || info!("line\n"));
+		assert_eq!(messages, ["line\n"]);
+	}
+
+	#[test]
+	fn level_num_follows_severity() {
+		let messages = capture(logger().with_level_numeric(true), || {
+			trace!("t");
+			debug!("d");
+			info!("i");
+			warn!("w");
+			error!("e");
+		});
+		assert_eq!(
+			messages,
+			[
+				"t  level_num=0",
+				"d  level_num=1",
+				"i  level_num=2",
+				"w  level_num=3",
+				"e  level_num=4",
+			]
+		);
+	}
+
+	#[test]
+	fn event_name_is_shown() {
+		let mut line = 0;
+		let messages = capture(logger().with_event_name(true), || {
+			line = line!() + 1;
+			info!("named");
+		});
+		assert_eq!(
+			messages,
+			[format!("named  event=event {}:{}", file!(), line)]
+		);
+	}
+
+	#[test]
+	fn module_path_is_independent_of_the_target() {
+		let logger = logger()
+			.with_module_path(true)
+			.fallback_message(FallbackMessage::Target);
+		let messages = capture(logger, || info!(target: "custom", a = 1));
+		assert_eq!(messages, [format!("custom  a=1 module={}", module_path!())]);
+	}
+
+	#[test]
+	fn skipped_spans_are_logged() {
+		let id = Id::from_u64(12345);
+		let messages = capture(logger(), || {
+			tracing::dispatcher::get_default(|dispatch| {
+				dispatch.enter(&id);
+				dispatch.exit(&id);
+			})
+		});
+		assert_eq!(
+			messages,
+			[
+				missing_span_message("on_enter", &id),
+				missing_span_message("on_exit", &id),
+			]
+		);
+	}
+}
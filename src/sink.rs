@@ -0,0 +1,74 @@
+use std::sync::Arc;
+
+/// A message as it's handed to os_log, for [sinks](Sink) to send elsewhere as well.
+#[derive(Clone, Copy, Debug)]
+pub struct LogRecord<'a> {
+	/// The subsystem of the logger the message was logged with.
+	pub subsystem: &'a str,
+	/// The category the message was logged to, e.g. one set with an `os_log.category` field.
+	pub category: &'a str,
+	/// The name Console uses for the os_log type, e.g. `"info"`.
+	pub log_type: &'static str,
+	/// The fully formatted message.
+	pub message: &'a str,
+}
+
+/// Somewhere besides os_log that messages are sent to, registered with
+/// [`OsLogger::with_sink`](crate::OsLogger::with_sink).
+pub trait Sink: Send + Sync {
+	fn emit(&self, record: &LogRecord);
+}
+
+impl<S: Sink + ?Sized> Sink for Arc<S> {
+	fn emit(&self, record: &LogRecord) {
+		(**self).emit(record)
+	}
+}
+
+impl<S: Sink + ?Sized> Sink for Box<S> {
+	fn emit(&self, record: &LogRecord) {
+		(**self).emit(record)
+	}
+}
+
+/// Fans each record out to all of its sinks, in order.
+impl<S: Sink> Sink for Vec<S> {
+	fn emit(&self, record: &LogRecord) {
+		for sink in self {
+			sink.emit(record);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parking_lot::Mutex;
+
+	#[derive(Default)]
+	struct Collect(Mutex<Vec<String>>);
+
+	impl Sink for Collect {
+		fn emit(&self, record: &LogRecord) {
+			self.0.lock().push(format!(
+				"{}/{}: {}",
+				record.category, record.log_type, record.message
+			));
+		}
+	}
+
+	#[test]
+	fn composite_reaches_every_sink() {
+		let first = Arc::new(Collect::default());
+		let second = Arc::new(Collect::default());
+		let composite: Vec<Box<dyn Sink>> = vec![Box::new(first.clone()), Box::new(second.clone())];
+		composite.emit(&LogRecord {
+			subsystem: "com.example.test",
+			category: "http",
+			log_type: "info",
+			message: "hello",
+		});
+		assert_eq!(*first.0.lock(), ["http/info: hello"]);
+		assert_eq!(*second.0.lock(), ["http/info: hello"]);
+	}
+}
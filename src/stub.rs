@@ -1,17 +1,42 @@
-use tracing_core::Subscriber;
+use crate::config::Config;
+use tracing_core::{Level, Subscriber};
 use tracing_subscriber::{layer::Layer, registry::LookupSpan};
 
-pub struct OsLogger;
+pub struct OsLogger {
+	pub(crate) config: Config,
+}
 
 impl OsLogger {
-	pub fn new<S, C>(_subsystem: S, _category: C) -> Self
+	pub fn new<S, C>(subsystem: S, category: C) -> Self
 	where
 		S: AsRef<str>,
 		C: AsRef<str>,
 	{
 		eprintln!("Initializing OsLogger on non-Apple platform! Nothing will be logged by it!");
-		Self
+		Self {
+			config: Config::new(subsystem.as_ref(), category.as_ref()),
+		}
+	}
+
+	pub fn label_current_activity<L>(_label: L)
+	where
+		L: AsRef<str>,
+	{
+	}
+
+	pub fn force_log(&self, _level: Level, _message: &str, _fields: &[(&str, &str)]) {}
+
+	pub fn dump_active_spans(&self) {}
+
+	pub fn recent_logs(&self) -> Vec<String> {
+		Vec::new()
 	}
+
+	pub fn dropped_deduped(&self) -> u64 {
+		0
+	}
+
+	pub fn shutdown(&self) {}
 }
 
 impl<S> Layer<S> for OsLogger where S: Subscriber + for<'a> LookupSpan<'a> {}
@@ -1,41 +1,171 @@
+use crate::config::{Config, MessageFormat};
 use std::{collections::BTreeMap, fmt::Debug};
 use tracing_core::field::{Field, Visit};
 
 pub type AttributeMap = BTreeMap<String, String>;
 
+/// Appended to field values cut short by the field length cap.
+const TRUNCATION_MARKER: &str = "…";
+
+/// Strips the quotes that string values are recorded with.
+pub fn unquoted(value: &str) -> &str {
+	value
+		.strip_prefix('"')
+		.and_then(|value| value.strip_suffix('"'))
+		.unwrap_or(value)
+}
+
+/// Cuts `value` short on a char boundary if it's over `max_bytes`.
+pub fn truncated(mut value: String, max_bytes: Option<usize>) -> String {
+	if let Some(max_bytes) = max_bytes {
+		if value.len() > max_bytes {
+			let mut end = max_bytes;
+			while !value.is_char_boundary(end) {
+				end -= 1;
+			}
+			value.truncate(end);
+			value.push_str(TRUNCATION_MARKER);
+		}
+	}
+	value
+}
+
+/// Returns what's between the quotes if `value` is the `Debug` output of a single char, escapes
+/// included.
+fn char_literal(value: &str) -> Option<&str> {
+	let inner = value.strip_prefix('\'')?.strip_suffix('\'')?;
+	let mut chars = inner.chars();
+	let well_formed = match (chars.next(), chars.next()) {
+		(Some('\\'), None) => false,
+		(Some(_), None) => true,
+		(Some('\\'), Some('\\' | '\'' | '0' | 'n' | 'r' | 't')) => chars.next().is_none(),
+		(Some('\\'), Some('u')) => chars
+			.as_str()
+			.strip_prefix('{')
+			.and_then(|code| code.strip_suffix('}'))
+			.is_some_and(|code| {
+				(1..=6).contains(&code.len()) && code.chars().all(|c| c.is_ascii_hexdigit())
+			}),
+		_ => false,
+	};
+	well_formed.then_some(inner)
+}
+
 pub struct FieldVisitor<'a> {
 	output: &'a mut AttributeMap,
+	config: &'a Config,
+	full_debug: bool,
 }
 
 impl<'a> FieldVisitor<'a> {
-	pub fn new(output: &'a mut AttributeMap) -> Self {
-		FieldVisitor { output }
+	pub fn new(output: &'a mut AttributeMap, config: &'a Config) -> Self {
+		FieldVisitor {
+			output,
+			config,
+			full_debug: false,
+		}
+	}
+
+	/// Sets whether `Debug` values are exempt from the field length cap.
+	pub fn full_debug(mut self, full_debug: bool) -> Self {
+		self.full_debug = full_debug;
+		self
+	}
+
+	/// The field length cap for `field`. Messages aren't capped, as the cap is for field values.
+	fn max_bytes(&self, field: &Field) -> Option<usize> {
+		match field.name() {
+			"message" => None,
+			_ => self.config.max_field_bytes,
+		}
+	}
+
+	fn insert(&mut self, field: &Field, value: String) {
+		let value = truncated(value, self.max_bytes(field));
+		self.output.insert(field.name().to_string(), value);
 	}
 }
 
 impl<'a> Visit for FieldVisitor<'a> {
+	fn record_f64(&mut self, field: &Field, value: f64) {
+		let value = self.config.numeric_format.apply(&format!("{:?}", value));
+		self.insert(field, value);
+	}
+
 	fn record_i64(&mut self, field: &Field, value: i64) {
-		self.output
-			.insert(field.name().to_string(), value.to_string());
+		let value = self.config.numeric_format.apply(&value.to_string());
+		self.insert(field, value);
 	}
 
 	fn record_u64(&mut self, field: &Field, value: u64) {
-		self.output
-			.insert(field.name().to_string(), value.to_string());
+		let value = self.config.numeric_format.apply(&value.to_string());
+		self.insert(field, value);
 	}
 
 	fn record_bool(&mut self, field: &Field, value: bool) {
-		self.output
-			.insert(field.name().to_string(), value.to_string());
+		self.insert(field, value.to_string());
 	}
 
 	fn record_str(&mut self, field: &Field, value: &str) {
-		self.output
-			.insert(field.name().to_string(), format!("\"{}\"", value));
+		// Truncated before quoting, so the quotes survive the cut.
+		let value = truncated(value.to_string(), self.max_bytes(field));
+		// Escaped like `Debug` strings when values are quoted, so both can be displayed the same.
+		let value =
+			if self.config.quote_values && self.config.message_format == MessageFormat::KeyValue {
+				format!("{:?}", value)
+			} else {
+				format!("\"{}\"", value)
+			};
+		self.output.insert(field.name().to_string(), value);
 	}
 
 	fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
-		self.output
-			.insert(field.name().to_string(), format!("{:?}", value));
+		let mut value = format!("{:?}", value);
+		// `Visit` has no `record_char`, so chars show up here as `'a'`. Messages are never chars,
+		// so they're left as written.
+		if field.name() != "message" {
+			if let Some(c) = char_literal(&value) {
+				value = c.to_string();
+			}
+		}
+		if self.full_debug {
+			self.output.insert(field.name().to_string(), value);
+		} else {
+			self.insert(field, value);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn char_literal_accepts_debug_chars() {
+		for (c, inner) in [
+			('a', "a"),
+			('"', "\""),
+			('\n', "\\n"),
+			('\'', "\\'"),
+			('\\', "\\\\"),
+		] {
+			assert_eq!(char_literal(&format!("{:?}", c)), Some(inner));
+		}
+		assert_eq!(char_literal(&format!("{:?}", '\u{7f}')), Some("\\u{7f}"));
+	}
+
+	#[test]
+	fn char_literal_rejects_other_quoted_text() {
+		for value in [
+			"'ab'",
+			"''",
+			"'\\'",
+			"'\\x'",
+			"'\\nn'",
+			"'\\u{}'",
+			"'\\u{zz}'",
+		] {
+			assert_eq!(char_literal(value), None, "{}", value);
+		}
 	}
 }
@@ -3,3 +3,11 @@
 void wrapped_os_log_with_type(os_log_t log, os_log_type_t type, const char* message) {
     os_log_with_type(log, type, "%{public}s", message);
 }
+
+const char* wrapped_current_queue_label(void) {
+    return dispatch_queue_get_label(DISPATCH_CURRENT_QUEUE_LABEL);
+}
+
+int wrapped_mach_tid(uint64_t* tid) {
+    return pthread_threadid_np(NULL, tid);
+}
@@ -1,3 +1,7 @@
 #include <os/log.h>
 #include <os/activity.h>
+#include <dispatch/dispatch.h>
+#include <pthread.h>
 void wrapped_os_log_with_type(os_log_t log, os_log_type_t type, const char* message);
+const char* wrapped_current_queue_label(void);
+int wrapped_mach_tid(uint64_t* tid);